@@ -0,0 +1,117 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use crate::request::UpgradeRequest;
+use crate::routes::{ConnectionId, State};
+
+pub use tokio_tungstenite::tungstenite::handshake::server::Request;
+pub use tokio_tungstenite::tungstenite::http::StatusCode;
+
+//the answer of the admission hook
+#[derive(Debug, Clone, PartialEq)]
+pub enum Admission {
+    Accept,
+    //accepts the upgrade with this connection id instead of a generated one (e.g. the user's id)
+    //if a connection with the same id is already connected, the upgrade is refused with 409
+    AcceptAs(ConnectionId),
+    //the upgrade is refused with the status code, the client never gets an id
+    Reject(StatusCode),
+}
+
+pub(crate) type AdmissionHook<S> =
+    Arc<dyn Fn(&UpgradeRequest, &State<S>) -> Admission + Send + Sync + 'static>;
+
+pub(crate) type IdGenerator = Arc<dyn Fn(&UpgradeRequest) -> ConnectionId + Send + Sync + 'static>;
+
+//the ids of the connections, from the handshake until they disconnect
+#[derive(Default)]
+pub(crate) struct Ids {
+    used: Mutex<HashSet<ConnectionId>>,
+}
+
+impl Ids {
+    //reserves the id, None if it's already used by another connection
+    //the id is freed when the returned guard is dropped
+    pub(crate) fn reserve(self: &Arc<Self>, id: ConnectionId) -> Option<ReservedId> {
+        if !self.used.lock().unwrap().insert(id.clone()) {
+            return None;
+        }
+        Some(ReservedId {
+            ids: self.clone(),
+            id,
+        })
+    }
+}
+
+pub(crate) struct ReservedId {
+    ids: Arc<Ids>,
+    pub(crate) id: ConnectionId,
+}
+
+impl Drop for ReservedId {
+    fn drop(&mut self) {
+        self.ids.used.lock().unwrap().remove(&self.id);
+    }
+}
+
+//connection counting for the max_connections and max_connections_per_ip limits
+pub(crate) struct Limits {
+    max_total: Option<usize>,
+    max_per_ip: Option<usize>,
+    //shared by every listener of the server
+    counts: Arc<Mutex<Counts>>,
+}
+
+#[derive(Default)]
+pub(crate) struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl Limits {
+    pub(crate) fn new(max_total: Option<usize>, max_per_ip: Option<usize>, counts: Arc<Mutex<Counts>>) -> Self {
+        Self {
+            max_total,
+            max_per_ip,
+            counts,
+        }
+    }
+
+    //reserves a place for the connection, or returns the status code to reject it with
+    //the place is freed when the returned slot is dropped
+    pub(crate) fn acquire(self: &Arc<Self>, ip: IpAddr) -> Result<Slot, StatusCode> {
+        let mut counts = self.counts.lock().unwrap();
+        if self.max_total.is_some_and(|max| counts.total >= max) {
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        let per_ip = counts.per_ip.get(&ip).copied().unwrap_or(0);
+        if self.max_per_ip.is_some_and(|max| per_ip >= max) {
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+        counts.total += 1;
+        counts.per_ip.insert(ip, per_ip + 1);
+        Ok(Slot {
+            limits: self.clone(),
+            ip,
+        })
+    }
+}
+
+//a reserved place of a connection
+pub(crate) struct Slot {
+    limits: Arc<Limits>,
+    ip: IpAddr,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut counts = self.limits.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(count) = counts.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
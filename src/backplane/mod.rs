@@ -0,0 +1,277 @@
+use crate::routes::ConnectionId;
+use futures_util::stream::BoxStream;
+use tokio::sync::broadcast;
+
+//connects several server instances (e.g. behind a load balancer), set with Server::backplane()
+//send_to() publishes the messages for connections that aren't connected to this instance,
+//room sends and broadcasts are published as well and every instance delivers them to its own clients
+pub trait Backplane: Send + Sync + 'static {
+    //sends the message to every instance, including this one (its own messages are skipped by the origin)
+    //it must not block, e.g. hand the message over to a publisher task
+    fn publish(&self, msg: BackplaneMessage);
+
+    //the messages published by every instance, called when the server starts
+    //errors go to the error hook of the server, the server subscribes again if the stream ends
+    fn subscribe(&self) -> BoxStream<'static, Result<BackplaneMessage, BackplaneError>>;
+}
+
+//an error of the broker, e.g. the connection to it was lost or a message couldn't be published
+pub type BackplaneError = Box<dyn std::error::Error + Send + Sync>;
+
+//a message that goes between the server instances
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackplaneMessage {
+    //the id of the instance that published it
+    pub origin: String,
+    pub target: Target,
+    pub msg: String,
+}
+
+//the recipients of a BackplaneMessage
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Connection(ConnectionId),
+    Room(String),
+    //the members of several rooms, every member gets the message once
+    Rooms(Vec<String>),
+    Broadcast { except: Option<ConnectionId> },
+}
+
+impl BackplaneMessage {
+    //the wire format for backplanes that carry strings: origin, kind, length of the argument, argument + message
+    //e.g. "node-1\nroom\n5\nlobby@CHAT #text hi"
+    //the argument of several rooms is the rooms with their length each, e.g. "5\nlobby4\nhall"
+    pub fn encode(&self) -> String {
+        let (kind, arg) = match &self.target {
+            Target::Connection(id) => ("to", id.to_string()),
+            Target::Room(room) => ("room", room.clone()),
+            Target::Rooms(rooms) => ("rooms", rooms.iter().map(|room| format!("{}\n{}", room.len(), room)).collect()),
+            Target::Broadcast { except: None } => ("all", String::new()),
+            Target::Broadcast { except: Some(id) } => ("except", id.to_string()),
+        };
+        format!("{}\n{}\n{}\n{}{}", self.origin, kind, arg.len(), arg, self.msg)
+    }
+
+    //None if it isn't a message in the wire format of encode()
+    pub fn decode(wire: &str) -> Option<Self> {
+        let mut parts = wire.splitn(4, '\n');
+        let origin = parts.next()?.to_string();
+        let kind = parts.next()?;
+        let len: usize = parts.next()?.parse().ok()?;
+        let rest = parts.next()?;
+        let (arg, msg) = (rest.get(..len)?, rest.get(len..)?);
+        let target = match kind {
+            "to" => Target::Connection(ConnectionId::from(arg)),
+            "room" => Target::Room(arg.to_string()),
+            "rooms" => Target::Rooms(decode_rooms(arg)?),
+            "all" => Target::Broadcast { except: None },
+            "except" => Target::Broadcast {
+                except: Some(ConnectionId::from(arg)),
+            },
+            _ => return None,
+        };
+        Some(Self {
+            origin,
+            target,
+            msg: msg.to_string(),
+        })
+    }
+}
+
+//the rooms of the "rooms" argument, each one after its length
+fn decode_rooms(mut arg: &str) -> Option<Vec<String>> {
+    let mut rooms = Vec::new();
+    while !arg.is_empty() {
+        let (len, rest) = arg.split_once('\n')?;
+        let len: usize = len.parse().ok()?;
+        rooms.push(rest.get(..len)?.to_string());
+        arg = rest.get(len..)?;
+    }
+    Some(rooms)
+}
+
+//a backplane for the servers of one process (tests, or several listeners in one binary)
+//every clone is connected to the same channel
+#[derive(Clone)]
+pub struct MemoryBackplane {
+    sender: broadcast::Sender<BackplaneMessage>,
+}
+
+impl MemoryBackplane {
+    pub fn new() -> Self {
+        //slow instances skip the messages they missed
+        let (sender, _) = broadcast::channel(1024);
+        Self { sender }
+    }
+}
+
+impl Default for MemoryBackplane {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backplane for MemoryBackplane {
+    fn publish(&self, msg: BackplaneMessage) {
+        let _ = self.sender.send(msg);
+    }
+
+    fn subscribe(&self) -> BoxStream<'static, Result<BackplaneMessage, BackplaneError>> {
+        let receiver = self.sender.subscribe();
+        Box::pin(futures_util::stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(msg) => return Some((Ok(msg), receiver)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }))
+    }
+}
+
+//a backplane on a redis pub/sub channel, behind the redis feature
+//the messages are published in order by one task, in the format of BackplaneMessage::encode()
+//the errors of publishing and subscribing go to the error hook, a lost connection is opened again
+#[cfg(feature = "redis")]
+pub struct RedisBackplane {
+    client: redis::Client,
+    channel: String,
+    publisher: tokio::sync::mpsc::UnboundedSender<String>,
+    //the publish errors go to the latest subscription
+    errors: std::sync::Arc<std::sync::Mutex<Option<tokio::sync::mpsc::UnboundedSender<redis::RedisError>>>>,
+}
+
+#[cfg(feature = "redis")]
+impl RedisBackplane {
+    //connects to the redis server (e.g. redis://127.0.0.1/), every instance has to use the same channel
+    //must be called inside the tokio runtime
+    pub async fn connect(url: &str, channel: impl Into<String>) -> redis::RedisResult<Self> {
+        use redis::AsyncCommands;
+
+        let client = redis::Client::open(url)?;
+        let mut connection = Some(client.get_multiplexed_async_connection().await?);
+        let channel = channel.into();
+        let (publisher, mut messages) = tokio::sync::mpsc::unbounded_channel::<String>();
+        let errors = std::sync::Arc::new(std::sync::Mutex::new(None::<tokio::sync::mpsc::UnboundedSender<_>>));
+        let publish_client = client.clone();
+        let publish_channel = channel.clone();
+        let publish_errors = errors.clone();
+        tokio::spawn(async move {
+            while let Some(msg) = messages.recv().await {
+                //the connection is opened again for the next message after it was lost
+                let result = match &mut connection {
+                    Some(connection) => connection.publish::<_, _, usize>(&publish_channel, msg).await,
+                    None => match publish_client.get_multiplexed_async_connection().await {
+                        Ok(reconnected) => {
+                            connection.insert(reconnected).publish::<_, _, usize>(&publish_channel, msg).await
+                        }
+                        Err(e) => Err(e),
+                    },
+                };
+                if let Err(e) = result {
+                    if e.is_unrecoverable_error() {
+                        connection = None;
+                    }
+                    if let Some(errors) = publish_errors.lock().unwrap().as_ref() {
+                        let _ = errors.send(e);
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            client,
+            channel,
+            publisher,
+            errors,
+        })
+    }
+}
+
+#[cfg(feature = "redis")]
+impl Backplane for RedisBackplane {
+    fn publish(&self, msg: BackplaneMessage) {
+        let _ = self.publisher.send(msg.encode());
+    }
+
+    //subscribes again when the subscription fails or the connection to redis is lost, the stream never ends
+    fn subscribe(&self) -> BoxStream<'static, Result<BackplaneMessage, BackplaneError>> {
+        let (errors, publish_errors) = tokio::sync::mpsc::unbounded_channel();
+        *self.errors.lock().unwrap() = Some(errors);
+        let subscription = RedisSubscription {
+            client: self.client.clone(),
+            channel: self.channel.clone(),
+            messages: None,
+            retry: None,
+            publish_errors,
+        };
+        Box::pin(futures_util::stream::unfold(subscription, |mut subscription| async move {
+            let next = subscription.next().await;
+            Some((next, subscription))
+        }))
+    }
+}
+
+//the first wait before subscribing again, it doubles up to the max while subscribing fails
+#[cfg(feature = "redis")]
+const REDIS_RETRY: std::time::Duration = std::time::Duration::from_millis(100);
+#[cfg(feature = "redis")]
+const REDIS_MAX_RETRY: std::time::Duration = std::time::Duration::from_secs(30);
+
+//the state of RedisBackplane::subscribe()
+#[cfg(feature = "redis")]
+struct RedisSubscription {
+    client: redis::Client,
+    channel: String,
+    //None until subscribed, and after the connection was lost
+    messages: Option<BoxStream<'static, redis::Msg>>,
+    //how long to wait before subscribing again, None after a success
+    retry: Option<std::time::Duration>,
+    publish_errors: tokio::sync::mpsc::UnboundedReceiver<redis::RedisError>,
+}
+
+#[cfg(feature = "redis")]
+impl RedisSubscription {
+    //takes the client and the channel instead of self, the stream of messages isn't Sync
+    async fn subscribe(client: &redis::Client, channel: &str) -> redis::RedisResult<BoxStream<'static, redis::Msg>> {
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub.subscribe(channel).await?;
+        Ok(Box::pin(pubsub.into_on_message()))
+    }
+
+    //the next message or error, (re)subscribes first if needed
+    async fn next(&mut self) -> Result<BackplaneMessage, BackplaneError> {
+        use futures_util::StreamExt;
+
+        loop {
+            let Some(messages) = &mut self.messages else {
+                if let Some(retry) = self.retry {
+                    tokio::time::sleep(retry).await;
+                }
+                match Self::subscribe(&self.client, &self.channel).await {
+                    Ok(messages) => {
+                        self.messages = Some(messages);
+                        self.retry = None;
+                    }
+                    Err(e) => {
+                        self.retry = Some(self.retry.map_or(REDIS_RETRY, |retry| (retry * 2).min(REDIS_MAX_RETRY)));
+                        return Err(e.into());
+                    }
+                }
+                continue;
+            };
+            let msg = tokio::select! {
+                msg = messages.next() => msg,
+                Some(e) = self.publish_errors.recv() => return Err(e.into()),
+            };
+            let Some(msg) = msg else {
+                self.messages = None;
+                self.retry = Some(REDIS_RETRY);
+                return Err("lost the connection to redis".into());
+            };
+            if let Some(msg) = msg.get_payload::<String>().ok().and_then(|wire| BackplaneMessage::decode(&wire)) {
+                return Ok(msg);
+            }
+        }
+    }
+}
@@ -1,23 +1,41 @@
+#[cfg(feature = "interception")]
 use std::ops::Deref;
 #[cfg(feature = "interception")]
 use crate::interceptor::{
     Interceptor,
     InterceptorResult,
     InterceptorType,
-    ServerInterceptor,
 };
 
 #[cfg(feature = "layers")]
 use crate::layer::ClientLayer;
 #[cfg(feature = "layers")]
 use crate::layer::LayerResult::{Cancel, Pass};
+use crate::handler::{into_callback, Handler};
+use crate::disconnect::{Disconnect, DisconnectKind};
 use crate::parser::Parsed;
-use crate::routes::{Dispatcher, Params, Route, State};
+use crate::resume::{RESUME_COMMAND, RESUME_HEADER};
+use crate::routes::{Dispatcher, Io, Params, Route, State, WebSocketConfig};
 use futures_util::{SinkExt, StreamExt};
 
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio_tungstenite::connect_async;
-use tokio_tungstenite::tungstenite::Message;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::error::UrlError;
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::{client_async_tls_with_config, MaybeTlsStream, WebSocketStream};
+#[cfg(feature = "deflate")]
+use crate::deflate::{self, Deflate};
+#[cfg(feature = "deflate")]
+use tokio_tungstenite::client_async_with_config;
+#[cfg(feature = "deflate")]
+use tokio_tungstenite::tungstenite::error::TlsError;
+#[cfg(feature = "deflate")]
+use tokio_tungstenite::tungstenite::protocol::Role;
 
 pub struct Connector<S> {
     url: String,
@@ -29,6 +47,10 @@ pub struct Connector<S> {
     #[cfg(feature = "layers")]
     layers: Vec<ClientLayer<S>>,
     state: State<S>,
+    unix_socket: Option<PathBuf>,
+    websocket_config: WebSocketConfig,
+    #[cfg(feature = "deflate")]
+    deflate: bool,
 }
 
 impl<S: Send + Sync + 'static> Connector<S> {
@@ -45,9 +67,31 @@ impl<S: Send + Sync + 'static> Connector<S> {
             incoming_ir: Arc::new(None),
             #[cfg(feature = "interception")]
             outgoing_ir: Arc::new(None),
+            unix_socket: None,
+            websocket_config: WebSocketConfig::default(),
+            #[cfg(feature = "deflate")]
+            deflate: false,
         }
     }
 
+    //connects over the unix domain socket instead of tcp
+    //the url is still used for the handshake (e.g. ws://localhost/chat)
+    #[cfg(unix)]
+    pub fn unix_socket(&mut self, path: impl Into<PathBuf>) {
+        self.unix_socket = Some(path.into());
+    }
+
+    //the protocol limits and buffer sizes of the websocket (max message and frame size, write buffers)
+    pub fn websocket_config(&mut self, config: WebSocketConfig) {
+        self.websocket_config = config;
+    }
+
+    //offers permessage-deflate to the server, the messages are compressed if the server accepts it
+    #[cfg(feature = "deflate")]
+    pub fn permessage_deflate(&mut self) {
+        self.deflate = true;
+    }
+
     //add new incomeing routes
 
     pub fn route<F, Fut>(&mut self, name: impl Into<String>, callback: F)
@@ -55,12 +99,15 @@ impl<S: Send + Sync + 'static> Connector<S> {
         F: Fn(Params, Dispatcher, State<S>) -> Fut + Send + Sync + 'static,
         Fut: Future<Output = ()> + Send + 'static,
     {
+        self.route_handler(name, callback);
+    }
+
+    //same as route(), but takes anything that implements Handler (e.g. a struct with its own fields)
+    pub fn route_handler(&mut self, name: impl Into<String>, handler: impl Handler<S, Dispatcher>) {
         let name = name.into();
         self.routes.push(Route {
             name,
-            callback: Arc::new(move |params, dispatcher, state| {
-                Box::pin(callback(params, dispatcher, state))
-            }),
+            callback: into_callback(handler),
         });
     }
 
@@ -84,6 +131,12 @@ impl<S: Send + Sync + 'static> Connector<S> {
         let routes = self.routes;
         let sender_clone = sender.clone();
         let url = Arc::new(self.url);
+        let unix_socket = self.unix_socket.clone();
+        let websocket_config = self.websocket_config;
+        #[cfg(feature = "deflate")]
+        let deflate = self.deflate;
+        //the server's last resume token (if it enabled session resumption), presented on reconnect
+        let mut resume_token: Option<String> = None;
         #[cfg(feature = "layers")]
         let layers = self.layers.clone();
 
@@ -91,7 +144,16 @@ impl<S: Send + Sync + 'static> Connector<S> {
         let _life_cycle = tokio::spawn(async move {
             loop {
                 //connect to the server
-                let (ws_stream, _) = match connect_async(url.to_string()).await {
+                let ws_stream = match open(
+                    &url,
+                    unix_socket.as_deref(),
+                    resume_token.as_deref(),
+                    websocket_config,
+                    #[cfg(feature = "deflate")]
+                    deflate,
+                )
+                .await
+                {
                     Ok(stream) => stream,
                     Err(e) => {
                         eprintln!("connect error: {}", e);
@@ -101,6 +163,7 @@ impl<S: Send + Sync + 'static> Connector<S> {
 
                 //split ws into read-write
                 let (mut write, mut read) = ws_stream.split();
+                let connected_at = Instant::now();
 
                 //clones the interceptor
                 #[cfg(feature = "interception")]
@@ -119,9 +182,9 @@ impl<S: Send + Sync + 'static> Connector<S> {
                     #[cfg(feature = "layers")]
                     let layers = layers.clone();
                     tokio::spawn(async move {
-                        #[cfg(feature = "layers")]
                         if run_layer(
                             "CONNECTED".to_string(),
+                            #[cfg(feature = "layers")]
                             layers.as_ref(),
                             dispatcher.clone(),
                             state.clone(),
@@ -134,9 +197,8 @@ impl<S: Send + Sync + 'static> Connector<S> {
                     });
                 }
 
-                //runs the actual connection inside
-                //the connection_result var can output why the connection closed
-                let connection_result = loop {
+                //runs the actual connection inside, breaks with the reason of the disconnect
+                let disconnect = loop {
                     tokio::select! {
 
 
@@ -164,11 +226,17 @@ impl<S: Send + Sync + 'static> Connector<S> {
                         //RECEIVING
                         //waits to get an alert from the WS
                         //tries to parse it and send out an alert
-                        Some(Ok(msg)) = read.next() => {
+                        msg = read.next() => {
 
                             let msg = match msg {
-                                Message::Text(t) => t,
-                                _ => break
+                                Some(Ok(Message::Text(t))) => t,
+                                Some(Ok(Message::Close(frame))) => {
+                                    break Disconnect::with_frame(DisconnectKind::Closed, frame.as_ref(), connected_at.elapsed());
+                                }
+                                //tungstenite answers the pings, other frames are ignored
+                                Some(Ok(_)) => continue,
+                                Some(Err(e)) => break Disconnect::error(e, connected_at.elapsed()),
+                                None => break Disconnect::new(DisconnectKind::Closed, connected_at.elapsed()),
                             };
 
                             #[cfg(feature = "interception")]
@@ -196,6 +264,11 @@ impl<S: Send + Sync + 'static> Connector<S> {
                             let params = parsed.params;
                             let command = parsed.command;
 
+                            if command == RESUME_COMMAND {
+                                resume_token = params.get("token").cloned();
+                                continue;
+                            }
+
 
                             //copy-s the appstate
                             //IMPORTANT! the appstate is not mutable! ONLY the fields of the state can be mut
@@ -204,7 +277,7 @@ impl<S: Send + Sync + 'static> Connector<S> {
 
 
                             //tries to find a route with the command name
-                            if let Some(found_route) = routes.iter().find(|route| route.name == command.to_string()) {
+                            if let Some(found_route) = routes.iter().find(|route| route.name == command) {
 
                                 //creates the future and runs it down
                                 let callback = found_route.callback.clone();
@@ -226,18 +299,15 @@ impl<S: Send + Sync + 'static> Connector<S> {
                                 }
                             }
 
-
-                        //on error or if connection to either direction lost, breaks the loop
-                        else => break
                     }
                 };
-                eprintln!("Connection error: {:?}", connection_result);
+                eprintln!("Connection closed");
 
                 //tries to find and alert the DISCONNECTED route
                 if let Some(found_route) = routes.iter().find(|route| route.name == "DISCONNECTED") {
                     #[cfg(feature = "layers")]
                     let layers = layers.clone();
-                    let params = Params::new();
+                    let params = disconnect.params();
                     let state = self.state.clone();
                     let callback = found_route.callback.clone();
                     let dispatcher = Dispatcher {
@@ -269,9 +339,67 @@ impl<S: Send + Sync + 'static> Connector<S> {
     }
 }
 
+//opens the websocket over tcp (with tls for wss://) or over the unix socket if there's one
+//with a resume token the server gives back the old connection id and the missed messages
+async fn open(
+    url: &str,
+    unix_socket: Option<&Path>,
+    resume_token: Option<&str>,
+    config: WebSocketConfig,
+    #[cfg(feature = "deflate")] deflate: bool,
+) -> Result<WebSocketStream<MaybeTlsStream<Box<dyn Io>>>, Error> {
+    let mut request = url.into_client_request()?;
+    if let Some(token) = resume_token
+        && let Ok(token) = token.parse()
+    {
+        request.headers_mut().insert(RESUME_HEADER, token);
+    }
+    #[cfg(feature = "deflate")]
+    if deflate {
+        request
+            .headers_mut()
+            .insert("sec-websocket-extensions", deflate::OFFER.parse().unwrap());
+    }
+    let stream: Box<dyn Io> = match unix_socket {
+        #[cfg(unix)]
+        Some(path) => Box::new(UnixStream::connect(path).await?),
+        _ => {
+            let uri = request.uri();
+            let host = uri.host().ok_or(Error::Url(UrlError::NoHostName))?;
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let port = uri
+                .port_u16()
+                .unwrap_or(if uri.scheme_str() == Some("wss") { 443 } else { 80 });
+            Box::new(TcpStream::connect((host, port)).await?)
+        }
+    };
+    //the compression sits between tungstenite and tls, so the tls is done here instead of by tungstenite
+    #[cfg(feature = "deflate")]
+    if deflate {
+        let stream: Box<dyn Io> = match request.uri().scheme_str() {
+            Some("wss") => {
+                let domain = request.uri().host().unwrap_or_default();
+                let domain = domain.trim_start_matches('[').trim_end_matches(']');
+                let connector = native_tls::TlsConnector::new().map_err(|e| Error::Tls(TlsError::Native(Box::new(e))))?;
+                let tls = tokio_native_tls::TlsConnector::from(connector)
+                    .connect(domain, stream)
+                    .await
+                    .map_err(|e| Error::Tls(TlsError::Native(Box::new(e))))?;
+                Box::new(tls)
+            }
+            _ => stream,
+        };
+        let stream: Box<dyn Io> = Box::new(Deflate::handshake(stream, Role::Client, &config));
+        let (ws_stream, _) = client_async_with_config(request, MaybeTlsStream::Plain(stream), Some(config)).await?;
+        return Ok(ws_stream);
+    }
+    let (ws_stream, _) = client_async_tls_with_config(request, stream, Some(config), None).await?;
+    Ok(ws_stream)
+}
+
+#[cfg(feature = "layers")]
 async fn run_layer<S: Send + Sync + 'static>(
     route: String,
-    #[cfg(feature = "layers")]
     layers: &Vec<ClientLayer<S>>,
     clientdisp: Dispatcher,
     state: State<S>,
@@ -279,12 +407,11 @@ async fn run_layer<S: Send + Sync + 'static>(
 ) -> bool {
     let mut params = params;
     println!("LAYERING CALLED");
-    #[cfg(feature = "layers")]
     for layer in layers {
         if layer.blocked.contains(&route) {
             return false;
         }
-        if !layer.allowed.contains(&route) && layer.allowed.len() > 0 {
+        if !layer.allowed.contains(&route) && !layer.allowed.is_empty() {
             return false;
         }
         let parsed = params.clone();
@@ -303,3 +430,14 @@ async fn run_layer<S: Send + Sync + 'static>(
     }
     true
 }
+
+//without the layers feature every route passes
+#[cfg(not(feature = "layers"))]
+async fn run_layer<S: Send + Sync + 'static>(
+    _route: String,
+    _clientdisp: Dispatcher,
+    _state: State<S>,
+    _params: Params,
+) -> bool {
+    true
+}
@@ -0,0 +1,452 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
+
+//permessage-deflate (RFC 7692) for tungstenite, which doesn't support extensions:
+//the stream sits between tungstenite and the socket and rewrites the frames,
+//outgoing data frames are compressed (with the RSV1 bit), compressed incoming ones are decompressed
+//every message is compressed on its own (no context takeover), the peer may use context takeover
+
+pub(crate) const EXTENSION: &str = "permessage-deflate";
+
+//the offer of the Connector
+#[cfg(feature = "client")]
+pub(crate) const OFFER: &str = "permessage-deflate; client_no_context_takeover";
+
+//the answer of the server to an acceptable offer
+#[cfg(feature = "server")]
+pub(crate) const RESPONSE: &str = "permessage-deflate; server_no_context_takeover";
+
+//smaller messages aren't worth compressing
+const MIN_SIZE: usize = 64;
+
+//the end of every compressed message, removed by the sender and added back by the receiver
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+//the transformed frames waiting to be written are limited, over that the writes wait for the socket
+const MAX_BUFFERED: usize = 256 * 1024;
+
+//a longer http head isn't scanned, tungstenite rejects it anyway
+const MAX_HEAD: usize = 64 * 1024;
+
+//true if the offers of the client (the Sec-WebSocket-Extensions headers) contain an acceptable permessage-deflate
+//offers that limit the server's window can't be accepted, the compressor always uses the full window
+#[cfg(feature = "server")]
+pub(crate) fn accepts<'a>(offers: impl Iterator<Item = &'a str>) -> bool {
+    offers.flat_map(|header| header.split(',')).any(|offer| {
+        let mut params = offer.split(';').map(str::trim);
+        params.next() == Some(EXTENSION)
+            && params.all(|param| {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                name.trim() != "server_max_window_bits" || value.trim().trim_matches('"') == "15"
+            })
+    })
+}
+
+enum Phase {
+    //the http handshake, the response head is scanned for the extension
+    Head(Vec<u8>),
+    //permessage-deflate was negotiated
+    Frames,
+    //it wasn't, the bytes just pass through
+    Plain,
+}
+
+pub(crate) struct Deflate<S> {
+    inner: S,
+    role: Role,
+    phase: Phase,
+    //the limits of the WebSocketConfig, checked before the frames are buffered
+    max_message_size: Option<usize>,
+    max_frame_size: Option<usize>,
+    compress: Compress,
+    decompress: Decompress,
+    //bytes read from the socket that aren't a complete frame yet
+    raw: Vec<u8>,
+    //bytes for tungstenite
+    readable: Vec<u8>,
+    read_pos: usize,
+    //a fragmented compressed message: the first byte, the mask and the payload so far
+    fragments: Option<(u8, Option<[u8; 4]>, Vec<u8>)>,
+    eof: bool,
+    //bytes written by tungstenite that aren't a complete frame yet
+    pending: Vec<u8>,
+    //bytes for the socket
+    writable: Vec<u8>,
+    write_pos: usize,
+}
+
+impl<S> Deflate<S> {
+    //wraps the stream before the http handshake, the response head decides if the extension is used
+    //(written by the server, read by the client)
+    pub(crate) fn handshake(inner: S, role: Role, config: &WebSocketConfig) -> Self {
+        Self::new(inner, role, Phase::Head(Vec::new()), config)
+    }
+
+    //wraps a stream the extension was already negotiated for (e.g. by hyper)
+    #[cfg(feature = "hyper")]
+    pub(crate) fn negotiated(inner: S, role: Role, config: &WebSocketConfig) -> Self {
+        Self::new(inner, role, Phase::Frames, config)
+    }
+
+    fn new(inner: S, role: Role, phase: Phase, config: &WebSocketConfig) -> Self {
+        Self {
+            inner,
+            role,
+            phase,
+            max_message_size: config.max_message_size,
+            max_frame_size: config.max_frame_size,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            raw: Vec::new(),
+            readable: Vec::new(),
+            read_pos: 0,
+            fragments: None,
+            eof: false,
+            pending: Vec::new(),
+            writable: Vec::new(),
+            write_pos: 0,
+        }
+    }
+
+    //the response head travels from the server to the client
+    fn scans_reads(&self) -> bool {
+        self.role == Role::Client
+    }
+
+    //passes the head through and returns the bytes after it, once the head is complete the phase changes
+    fn scan_head<'a>(&mut self, data: &'a [u8], out: &mut Vec<u8>) -> &'a [u8] {
+        let Phase::Head(head) = &mut self.phase else {
+            return data;
+        };
+        let tail = &head[head.len().saturating_sub(3)..];
+        let joined = [tail, data].concat();
+        let Some(end) = joined.windows(4).position(|window| window == b"\r\n\r\n") else {
+            head.extend_from_slice(data);
+            out.extend_from_slice(data);
+            if head.len() > MAX_HEAD {
+                self.phase = Phase::Plain;
+            }
+            return &[];
+        };
+        let end = end + 4 - tail.len();
+        head.extend_from_slice(&data[..end]);
+        out.extend_from_slice(&data[..end]);
+        self.phase = if negotiated(head) { Phase::Frames } else { Phase::Plain };
+        &data[end..]
+    }
+
+    //moves the read bytes to tungstenite, the complete frames are decompressed
+    fn process_reads(&mut self) -> io::Result<()> {
+        if matches!(self.phase, Phase::Head(_)) {
+            if !self.scans_reads() {
+                self.readable.append(&mut self.raw);
+                return Ok(());
+            }
+            let raw = std::mem::take(&mut self.raw);
+            let mut readable = std::mem::take(&mut self.readable);
+            self.raw = self.scan_head(&raw, &mut readable).to_vec();
+            self.readable = readable;
+        }
+        if matches!(self.phase, Phase::Plain) {
+            self.readable.append(&mut self.raw);
+            return Ok(());
+        }
+        let mut start = 0;
+        while let Some(frame) = Frame::parse(&self.raw[start..]) {
+            //too big frames are refused before they're buffered
+            if self.max_frame_size.is_some_and(|max| frame.payload_len > max) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too big"));
+            }
+            if self.raw.len() - start < frame.len() {
+                break;
+            }
+            let bytes = &self.raw[start..start + frame.len()];
+            let payload = frame.payload(bytes);
+            let opcode = frame.first & 0x0f;
+            let fin = frame.first & 0x80 != 0;
+            let rsv1 = frame.first & 0x40 != 0;
+            match &mut self.fragments {
+                //control frames can come between the fragments
+                Some(_) if opcode >= 0x8 => self.readable.extend_from_slice(bytes),
+                Some((_, _, compressed)) if opcode == 0x0 => {
+                    compressed.extend_from_slice(&payload);
+                    if self.max_message_size.is_some_and(|max| compressed.len() > max) {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "message too big"));
+                    }
+                    if fin {
+                        let (first, mask, compressed) = self.fragments.take().unwrap();
+                        let message = inflate(&mut self.decompress, &compressed, self.max_message_size)?;
+                        Frame::write(&mut self.readable, 0x80 | (first & 0x0f), mask, &message);
+                    }
+                }
+                None if rsv1 && (opcode == 0x1 || opcode == 0x2) => {
+                    if fin {
+                        let message = inflate(&mut self.decompress, &payload, self.max_message_size)?;
+                        Frame::write(&mut self.readable, frame.first & !0x40, frame.mask, &message);
+                    } else {
+                        self.fragments = Some((frame.first, frame.mask, payload));
+                    }
+                }
+                //everything else is left to tungstenite
+                _ => self.readable.extend_from_slice(bytes),
+            }
+            start += frame.len();
+        }
+        self.raw.drain(..start);
+        Ok(())
+    }
+
+    //takes the bytes written by tungstenite, the complete data frames are compressed
+    fn process_writes(&mut self, data: &[u8]) {
+        let data = match self.phase {
+            Phase::Head(_) if !self.scans_reads() => {
+                let mut writable = std::mem::take(&mut self.writable);
+                let rest = self.scan_head(data, &mut writable);
+                self.writable = writable;
+                rest
+            }
+            Phase::Head(_) | Phase::Plain => {
+                self.writable.extend_from_slice(data);
+                return;
+            }
+            Phase::Frames => data,
+        };
+        if matches!(self.phase, Phase::Plain) {
+            self.writable.extend_from_slice(data);
+            return;
+        }
+        self.pending.extend_from_slice(data);
+        let mut start = 0;
+        while let Some(frame) = Frame::parse(&self.pending[start..])
+            && self.pending.len() - start >= frame.len()
+        {
+            let bytes = &self.pending[start..start + frame.len()];
+            let opcode = frame.first & 0x0f;
+            //whole data messages only, fragmented messages are sent uncompressed
+            if (opcode == 0x1 || opcode == 0x2) && frame.first & 0xc0 == 0x80 && frame.payload_len >= MIN_SIZE {
+                let compressed = deflate(&mut self.compress, &frame.payload(bytes));
+                Frame::write(&mut self.writable, frame.first | 0x40, frame.mask, &compressed);
+            } else {
+                self.writable.extend_from_slice(bytes);
+            }
+            start += frame.len();
+        }
+        self.pending.drain(..start);
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Deflate<S> {
+    //writes the transformed bytes to the socket
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.writable.len() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.writable[self.write_pos..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += written;
+        }
+        self.writable.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Deflate<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.readable.len() {
+                let n = buf.remaining().min(this.readable.len() - this.read_pos);
+                buf.put_slice(&this.readable[this.read_pos..this.read_pos + n]);
+                this.read_pos += n;
+                if this.read_pos == this.readable.len() {
+                    this.readable.clear();
+                    this.read_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            let mut chunk = [0u8; 8192];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                //an unfinished frame is handed over as it is, tungstenite reports the error
+                this.eof = true;
+                this.readable.append(&mut this.raw);
+                continue;
+            }
+            this.raw.extend_from_slice(read.filled());
+            this.process_reads()?;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Deflate<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.writable.len() - this.write_pos >= MAX_BUFFERED {
+            ready!(this.poll_drain(cx))?;
+        }
+        this.process_writes(buf);
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+//true if the response head accepts permessage-deflate
+fn negotiated(head: &[u8]) -> bool {
+    String::from_utf8_lossy(head).lines().any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("sec-websocket-extensions")
+                && value.split(',').any(|extension| extension.split(';').next().map(str::trim) == Some(EXTENSION))
+        })
+    })
+}
+
+//the header of a complete websocket frame
+struct Frame {
+    //fin, rsv1-3 and the opcode
+    first: u8,
+    mask: Option<[u8; 4]>,
+    header_len: usize,
+    payload_len: usize,
+}
+
+impl Frame {
+    //the header at the start of the buffer, None if it isn't complete yet (the payload may not be)
+    fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 2 {
+            return None;
+        }
+        let masked = buf[1] & 0x80 != 0;
+        let (payload_len, mut header_len) = match buf[1] & 0x7f {
+            126 => (u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?) as usize, 4),
+            127 => (u64::from_be_bytes(buf.get(2..10)?.try_into().ok()?) as usize, 10),
+            len => (len as usize, 2),
+        };
+        let mask = if masked {
+            let mask = buf.get(header_len..header_len + 4)?.try_into().ok()?;
+            header_len += 4;
+            Some(mask)
+        } else {
+            None
+        };
+        Some(Self {
+            first: buf[0],
+            mask,
+            header_len,
+            payload_len,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.header_len.saturating_add(self.payload_len)
+    }
+
+    //the unmasked payload of the frame's bytes
+    fn payload(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut payload = bytes[self.header_len..self.len()].to_vec();
+        if let Some(mask) = self.mask {
+            apply_mask(&mut payload, mask);
+        }
+        payload
+    }
+
+    fn write(out: &mut Vec<u8>, first: u8, mask: Option<[u8; 4]>, payload: &[u8]) {
+        out.push(first);
+        let masked = if mask.is_some() { 0x80 } else { 0x00 };
+        match payload.len() {
+            len if len < 126 => out.push(masked | len as u8),
+            len if len <= u16::MAX as usize => {
+                out.push(masked | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                out.push(masked | 127);
+                out.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        if let Some(mask) = mask {
+            out.extend_from_slice(&mask);
+        }
+        let start = out.len();
+        out.extend_from_slice(payload);
+        if let Some(mask) = mask {
+            apply_mask(&mut out[start..], mask);
+        }
+    }
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+//compresses one message on its own
+fn deflate(compress: &mut Compress, data: &[u8]) -> Vec<u8> {
+    compress.reset();
+    let mut out = Vec::with_capacity(data.len() / 2 + 64);
+    loop {
+        let consumed = compress.total_in() as usize;
+        if out.capacity() - out.len() < 64 {
+            out.reserve(out.capacity().max(1024));
+        }
+        //only fails on invalid parameters, which can't happen here
+        let _ = compress.compress_vec(&data[consumed..], &mut out, FlushCompress::Sync);
+        if compress.total_in() as usize == data.len() && out.len() < out.capacity() {
+            break;
+        }
+    }
+    if out.ends_with(&TAIL) {
+        out.truncate(out.len() - TAIL.len());
+    }
+    out
+}
+
+//decompresses one message, the decompressor keeps its window for the peers that use context takeover
+fn inflate(decompress: &mut Decompress, data: &[u8], max_size: Option<usize>) -> io::Result<Vec<u8>> {
+    let input = [data, &TAIL].concat();
+    let start = decompress.total_in();
+    let mut out = Vec::with_capacity(data.len() * 2 + 64);
+    loop {
+        let consumed = (decompress.total_in() - start) as usize;
+        if out.len() == out.capacity() {
+            out.reserve(out.capacity().max(1024));
+        }
+        let written = out.len();
+        decompress
+            .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if max_size.is_some_and(|max| out.len() > max) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "decompressed message too big"));
+        }
+        let done = (decompress.total_in() - start) as usize == input.len();
+        if done && out.len() < out.capacity() {
+            return Ok(out);
+        }
+        if !done && out.len() == written && (decompress.total_in() - start) as usize == consumed {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid compressed message"));
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use crate::routes::{CloseCode, Params};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+//why a connection ended, DISCONNECTED gets it as parameters on the server and the client:
+//#kind, #reason, #duration (milliseconds), #code (if there was a close frame) and #error (for errors)
+//Disconnect::from_params() reads them back in the route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disconnect {
+    pub kind: DisconnectKind,
+    //the code of the close frame that ended the connection (sent or received), None without a close frame
+    pub code: Option<CloseCode>,
+    //the reason of the close frame, or the name of the kind if it had none
+    pub reason: String,
+    //the error of DisconnectKind::Error
+    pub error: Option<String>,
+    //how long the connection was open (on the server, with session resumption, since the first connect)
+    pub duration: Duration,
+}
+
+//what ended the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectKind {
+    //the other side closed the connection
+    Closed,
+    //network or protocol error, the connection dropped without a close frame
+    Error,
+    //the client didn't answer the ping in time (server heartbeat)
+    PingTimeout,
+    //the client didn't send anything for too long
+    IdleTimeout,
+    //the client went over the rate limit with RateLimitPolicy::Disconnect
+    RateLimited,
+    //the client didn't read fast enough with QueuePolicy::Disconnect
+    SlowConsumer,
+    //closed by the server code, with close() or close_client()
+    Kicked,
+    //the server shut down
+    Shutdown,
+}
+
+impl DisconnectKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectKind::Closed => "closed",
+            DisconnectKind::Error => "error",
+            DisconnectKind::PingTimeout => "ping timeout",
+            DisconnectKind::IdleTimeout => "idle timeout",
+            DisconnectKind::RateLimited => "rate limited",
+            DisconnectKind::SlowConsumer => "slow consumer",
+            DisconnectKind::Kicked => "kicked",
+            DisconnectKind::Shutdown => "shutdown",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        [
+            DisconnectKind::Closed,
+            DisconnectKind::Error,
+            DisconnectKind::PingTimeout,
+            DisconnectKind::IdleTimeout,
+            DisconnectKind::RateLimited,
+            DisconnectKind::SlowConsumer,
+            DisconnectKind::Kicked,
+            DisconnectKind::Shutdown,
+        ]
+        .into_iter()
+        .find(|known| known.as_str() == kind)
+    }
+}
+
+impl Disconnect {
+    //a disconnect without a close frame
+    pub(crate) fn new(kind: DisconnectKind, duration: Duration) -> Self {
+        Self {
+            kind,
+            code: None,
+            reason: kind.as_str().to_string(),
+            error: None,
+            duration,
+        }
+    }
+
+    //a disconnect with the close frame that was sent or received
+    pub(crate) fn with_frame(kind: DisconnectKind, frame: Option<&CloseFrame>, duration: Duration) -> Self {
+        let mut disconnect = Self::new(kind, duration);
+        if let Some(frame) = frame {
+            disconnect.code = Some(frame.code);
+            if !frame.reason.is_empty() {
+                disconnect.reason = frame.reason.to_string();
+            }
+        }
+        disconnect
+    }
+
+    //a network or protocol error
+    pub(crate) fn error(error: impl ToString, duration: Duration) -> Self {
+        Self {
+            error: Some(error.to_string()),
+            ..Self::new(DisconnectKind::Error, duration)
+        }
+    }
+
+    //the parameters of DISCONNECTED
+    pub(crate) fn params(&self) -> Params {
+        let mut params = Params::from([
+            ("kind".to_string(), self.kind.as_str().to_string()),
+            ("reason".to_string(), self.reason.clone()),
+            ("duration".to_string(), self.duration.as_millis().to_string()),
+        ]);
+        if let Some(code) = self.code {
+            params.insert("code".to_string(), u16::from(code).to_string());
+        }
+        if let Some(error) = &self.error {
+            params.insert("error".to_string(), error.clone());
+        }
+        params
+    }
+
+    //reads the disconnect from the parameters of DISCONNECTED, None if they aren't there
+    pub fn from_params(params: &Params) -> Option<Self> {
+        let code = match params.get("code") {
+            Some(code) => Some(CloseCode::from(code.parse::<u16>().ok()?)),
+            None => None,
+        };
+        Some(Self {
+            kind: DisconnectKind::parse(params.get("kind")?)?,
+            code,
+            reason: params.get("reason")?.clone(),
+            error: params.get("error").cloned(),
+            duration: Duration::from_millis(params.get("duration")?.parse().ok()?),
+        })
+    }
+}
@@ -0,0 +1,180 @@
+use crate::admission::StatusCode;
+use crate::error::ServerError;
+use crate::request::UpgradeRequest;
+use crate::routes::Io;
+use crate::server::Running;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE};
+#[cfg(feature = "deflate")]
+use hyper::header::SEC_WEBSOCKET_EXTENSIONS;
+use hyper::{HeaderMap, Method, Request, Response};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::WebSocketStream;
+#[cfg(feature = "deflate")]
+use crate::deflate::{self, Deflate};
+
+//upgrades the requests of an existing hyper or axum server into wroustr connections
+//created with Server::upgrader(), the connections run on the same routes, layers and interceptors as with serve()
+//it's a tower and a hyper service, e.g. axum::Router::new().route_service("/ws", upgrader)
+pub struct Upgrader<S> {
+    inner: Arc<Inner<S>>,
+}
+
+struct Inner<S> {
+    //taken by shutdown()
+    running: Mutex<Option<Running<S>>>,
+}
+
+impl<S> Clone for Upgrader<S> {
+    fn clone(&self) -> Self {
+        Upgrader {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S: Send + Sync + 'static> Upgrader<S> {
+    pub(crate) fn new(running: Running<S>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                running: Mutex::new(Some(running)),
+            }),
+        }
+    }
+
+    //answers the upgrade request with 101 and spawns the connection, or rejects it with an http status
+    //the peer address is read from the request extensions (a SocketAddr, or axum's ConnectInfo)
+    pub fn upgrade<B>(&self, mut request: Request<B>) -> Response<Full<Bytes>> {
+        let Some(accept) = upgrade_key(request.method(), request.headers()).map(|key| derive_accept_key(key.as_bytes())) else {
+            return status(StatusCode::BAD_REQUEST);
+        };
+        let (handshake, conn_guard) = match self.inner.running.lock().unwrap().as_ref() {
+            Some(running) => (running.handshake.clone(), running.conns_tx.clone()),
+            None => return status(StatusCode::SERVICE_UNAVAILABLE),
+        };
+        let peer = peer_addr(&request);
+        #[cfg(feature = "deflate")]
+        let deflate = handshake.deflate(request.headers());
+        let upgrade = UpgradeRequest::new(request.uri(), request.headers(), peer);
+        let admitted = match handshake.admit(&upgrade) {
+            Ok(admitted) => admitted,
+            Err(rejected) => return status(rejected),
+        };
+
+        let on_upgrade = hyper::upgrade::on(&mut request);
+        tokio::spawn(async move {
+            let _conn_guard = conn_guard;
+            //hyper hands over the connection after the 101 response was sent
+            let upgraded = tokio::select! {
+                upgraded = on_upgrade => upgraded,
+                _ = handshake.shared.stopping() => return,
+            };
+            let upgraded = match upgraded {
+                Ok(upgraded) => upgraded,
+                Err(e) => {
+                    handshake.report(ServerError::Upgrade(e), peer).await;
+                    return;
+                }
+            };
+            let config = handshake.websocket_config;
+            #[cfg(feature = "deflate")]
+            let stream: Box<dyn Io> = match deflate {
+                true => Box::new(Deflate::negotiated(TokioIo::new(upgraded), Role::Server, &config)),
+                false => Box::new(TokioIo::new(upgraded)),
+            };
+            #[cfg(not(feature = "deflate"))]
+            let stream: Box<dyn Io> = Box::new(TokioIo::new(upgraded));
+            let ws = WebSocketStream::from_raw_socket(stream, Role::Server, Some(config)).await;
+            admitted.run(handshake.shared.clone(), ws, upgrade).await;
+        });
+
+        let mut response = Response::new(Full::default());
+        *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+        let headers = response.headers_mut();
+        headers.insert(CONNECTION, "upgrade".parse().unwrap());
+        headers.insert(UPGRADE, "websocket".parse().unwrap());
+        headers.insert(SEC_WEBSOCKET_ACCEPT, accept.parse().unwrap());
+        #[cfg(feature = "deflate")]
+        if deflate {
+            headers.insert(SEC_WEBSOCKET_EXTENSIONS, deflate::RESPONSE.parse().unwrap());
+        }
+        response
+    }
+
+    //shuts down the connections the same way as serve_with_shutdown(), later upgrades are rejected with 503
+    //the http server itself keeps running
+    pub async fn shutdown(&self) {
+        let running = self.inner.running.lock().unwrap().take();
+        if let Some(running) = running {
+            running.shutdown().await;
+        }
+    }
+}
+
+impl<S: Send + Sync + 'static, B> tower_service::Service<Request<B>> for Upgrader<S> {
+    type Response = Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        ready(Ok(self.upgrade(request)))
+    }
+}
+
+impl<S: Send + Sync + 'static, B> hyper::service::Service<Request<B>> for Upgrader<S> {
+    type Response = Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Infallible>>;
+
+    fn call(&self, request: Request<B>) -> Self::Future {
+        ready(Ok(self.upgrade(request)))
+    }
+}
+
+//the Sec-WebSocket-Key of a valid websocket upgrade request
+fn upgrade_key<'a>(method: &Method, headers: &'a HeaderMap) -> Option<&'a str> {
+    let has = |name, token: &str| {
+        headers.get_all(name).iter().any(|value| {
+            value
+                .to_str()
+                .is_ok_and(|value| value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token)))
+        })
+    };
+    if method != Method::GET || !has(CONNECTION, "upgrade") || !has(UPGRADE, "websocket") {
+        return None;
+    }
+    if headers.get(SEC_WEBSOCKET_VERSION).is_none_or(|version| version != "13") {
+        return None;
+    }
+    headers.get(SEC_WEBSOCKET_KEY)?.to_str().ok()
+}
+
+fn peer_addr<B>(request: &Request<B>) -> SocketAddr {
+    #[cfg(feature = "axum")]
+    if let Some(axum::extract::ConnectInfo(peer)) = request.extensions().get::<axum::extract::ConnectInfo<SocketAddr>>() {
+        return *peer;
+    }
+    request
+        .extensions()
+        .get::<SocketAddr>()
+        .copied()
+        .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)))
+}
+
+fn status(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::from(status.canonical_reason().unwrap_or_default()));
+    *response.status_mut() = status;
+    response
+}
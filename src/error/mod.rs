@@ -0,0 +1,85 @@
+use std::fmt::{Display, Formatter};
+#[cfg(feature = "server")]
+use crate::backplane::BackplaneError;
+#[cfg(feature = "server")]
+use tokio_tungstenite::tungstenite;
+
+//errors returned by Server::serve() or passed to the error hook
+#[cfg(feature = "server")]
+#[derive(Debug)]
+pub enum ServerError {
+    //the listener couldn't bind to the address (e.g. the port is busy)
+    Bind { addr: String, source: std::io::Error },
+    //accepting a connection failed: returned by serve() if the listener is broken,
+    //passed to the error hook for the failures of single connections
+    Io(std::io::Error),
+    //the tls acceptor couldn't be created or the tls handshake of a client failed
+    #[cfg(feature = "native-tls")]
+    Tls(native_tls::Error),
+    //a client connected, but the websocket handshake failed
+    Handshake(tungstenite::Error),
+    //a client didn't finish the tls or the websocket handshake in time (see Server::handshake_timeout())
+    HandshakeTimeout,
+    //hyper couldn't hand over an upgraded connection (Upgrader)
+    #[cfg(feature = "hyper")]
+    Upgrade(hyper::Error),
+    //the backplane couldn't publish or deliver messages, it keeps trying (see Server::backplane())
+    Backplane(BackplaneError),
+}
+
+#[cfg(feature = "server")]
+impl Display for ServerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::Bind { addr, source } => write!(f, "failed to bind to {}: {}", addr, source),
+            ServerError::Io(e) => write!(f, "io error: {}", e),
+            #[cfg(feature = "native-tls")]
+            ServerError::Tls(e) => write!(f, "tls error: {}", e),
+            ServerError::Handshake(e) => write!(f, "websocket handshake failed: {}", e),
+            ServerError::HandshakeTimeout => write!(f, "handshake timed out"),
+            #[cfg(feature = "hyper")]
+            ServerError::Upgrade(e) => write!(f, "http upgrade failed: {}", e),
+            ServerError::Backplane(e) => write!(f, "backplane error: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Bind { source, .. } => Some(source),
+            ServerError::Io(e) => Some(e),
+            #[cfg(feature = "native-tls")]
+            ServerError::Tls(e) => Some(e),
+            ServerError::Handshake(e) => Some(e),
+            ServerError::HandshakeTimeout => None,
+            #[cfg(feature = "hyper")]
+            ServerError::Upgrade(e) => Some(e),
+            ServerError::Backplane(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+//why a message couldn't be sent, returned by the try_ sends of the dispatchers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    //there's no connection with the id (it never existed or it's already gone)
+    UnknownConnection,
+    //the connection is closing or closed
+    Closed,
+    //the outgoing queue of the connection is full (see Server::outgoing_queue())
+    QueueFull,
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::UnknownConnection => write!(f, "unknown connection"),
+            SendError::Closed => write!(f, "connection closed"),
+            SendError::QueueFull => write!(f, "outgoing queue full"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
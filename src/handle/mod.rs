@@ -0,0 +1,93 @@
+use crate::error::SendError;
+use crate::hub::Hub;
+use crate::routes::{CloseCode, ConnectionId, ConnectionInfo, GlobalDisp};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+//sends messages to the clients of a server from outside the route handlers (background jobs, timers, ...)
+//created with Server::handle(), cheap to clone
+#[derive(Clone)]
+pub struct ServerHandle {
+    hub: Arc<Hub>,
+    stop: Arc<watch::Sender<bool>>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(hub: Arc<Hub>, stop: Arc<watch::Sender<bool>>) -> Self {
+        Self { hub, stop }
+    }
+
+    //sends the message to the connection with the id, dropped if it can't be delivered
+    pub fn send_to(&self, msg: impl Into<String>, id: impl Into<ConnectionId>) {
+        let _ = self.try_send_to(msg, id);
+    }
+
+    //same as send_to(), but tells why the message couldn't be queued (UnknownConnection, Closed or QueueFull)
+    pub fn try_send_to(&self, msg: impl Into<String>, id: impl Into<ConnectionId>) -> Result<(), SendError> {
+        self.hub.send_to(&id.into(), msg.into().into())
+    }
+
+    //sends the message to every connected client
+    pub fn broadcast(&self, msg: impl Into<String>) {
+        self.hub.deliver(GlobalDisp::Broadcast {
+            msg: msg.into().into(),
+            except: None,
+        });
+    }
+
+    //sends the message to everyone in the room
+    pub fn broadcast_to_room(&self, room: impl Into<String>, msg: impl Into<String>) {
+        self.hub.deliver(GlobalDisp::Room {
+            room: room.into(),
+            msg: msg.into().into(),
+        });
+    }
+
+    //every connection in the room
+    pub fn room_members(&self, room: impl Into<String>) -> Vec<ConnectionId> {
+        self.hub.rooms.lock().unwrap().members(&room.into())
+    }
+
+    //closes the connection with the code and the reason, DISCONNECTED runs with the reason
+    pub fn close_client(
+        &self,
+        id: impl Into<ConnectionId>,
+        code: CloseCode,
+        reason: impl Into<String>,
+    ) -> Result<(), SendError> {
+        let frame = CloseFrame {
+            code,
+            reason: reason.into().into(),
+        };
+        self.hub.close(&id.into(), frame)
+    }
+
+    //the ids of every connected client
+    pub fn connection_ids(&self) -> Vec<ConnectionId> {
+        self.hub.ids()
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.hub.count()
+    }
+
+    pub fn is_online(&self, id: impl Into<ConnectionId>) -> bool {
+        self.hub.is_online(&id.into())
+    }
+
+    pub fn connection_info(&self, id: impl Into<ConnectionId>) -> Option<ConnectionInfo> {
+        self.hub.info(&id.into())
+    }
+
+    //the presence status of a connected client, None if it isn't online
+    pub fn status_of(&self, id: impl Into<ConnectionId>) -> Option<String> {
+        self.hub.status(&id.into())
+    }
+
+    //shuts the server down gracefully, the same way as the signal of serve_with_shutdown()
+    //serve() returns once the connections are closed; called before serve(), the server stops right away
+    pub fn shutdown(&self) {
+        self.stop.send_replace(true);
+    }
+}
@@ -0,0 +1,64 @@
+#[cfg(any(feature = "server", feature = "client"))]
+use crate::routes::Callback;
+use crate::routes::{Params, State};
+use std::sync::Arc;
+
+//everything a route receives when it's called
+//closures get these as separate arguments, Handler structs get them bundled together
+pub struct Context<S, D> {
+    pub params: Params,
+    pub dispatcher: D,
+    pub state: State<S>,
+}
+
+//a route handler that can carry its own fields (db pools, clients, configs...)
+//D is the dispatcher: Dispatcher on the client, ServerDispatcher on the server
+//
+//closures with the (params, dispatcher, state) signature implement it automatically,
+//so the same handler can be used with route() and route_handler()
+pub trait Handler<S, D>: Send + Sync + 'static {
+    fn call(&self, ctx: Context<S, D>) -> impl Future<Output = ()> + Send;
+}
+
+impl<S, D, F, Fut> Handler<S, D> for F
+where
+    F: Fn(Params, D, State<S>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    fn call(&self, ctx: Context<S, D>) -> impl Future<Output = ()> + Send {
+        self(ctx.params, ctx.dispatcher, ctx.state)
+    }
+}
+
+//lets one handler instance be shared between several routes
+impl<S, D, H> Handler<S, D> for Arc<H>
+where
+    H: Handler<S, D>,
+{
+    fn call(&self, ctx: Context<S, D>) -> impl Future<Output = ()> + Send {
+        self.as_ref().call(ctx)
+    }
+}
+
+//wraps a handler into the boxed callback stored in the route lists
+#[cfg(any(feature = "server", feature = "client"))]
+pub(crate) fn into_callback<S, D, H>(handler: H) -> Callback<D, S>
+where
+    S: Send + Sync + 'static,
+    D: Send + 'static,
+    H: Handler<S, D>,
+{
+    let handler = Arc::new(handler);
+    Arc::new(move |params, dispatcher, state| {
+        let handler = handler.clone();
+        Box::pin(async move {
+            handler
+                .call(Context {
+                    params,
+                    dispatcher,
+                    state,
+                })
+                .await
+        })
+    })
+}
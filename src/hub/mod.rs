@@ -0,0 +1,340 @@
+use crate::backplane::{Backplane, BackplaneError, BackplaneMessage, Target};
+use crate::disconnect::DisconnectKind;
+use crate::error::SendError;
+use crate::presence::{Presence, PresenceEvent};
+use crate::queue::Outbox;
+use crate::room::Rooms;
+use crate::routes::{ConnectionId, ConnectionInfo, GlobalDisp};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use futures_util::StreamExt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Utf8Bytes;
+use uuid::Uuid;
+
+//the first wait before subscribing to the backplane again, it doubles up to the max while the stream keeps ending
+const SUBSCRIBE_RETRY: Duration = Duration::from_millis(100);
+const SUBSCRIBE_MAX_RETRY: Duration = Duration::from_secs(30);
+
+//the server's side of a connected client
+pub(crate) struct Connection {
+    pub(crate) outbox: Arc<Outbox>,
+    //the round-trip time of the last answered ping
+    pub(crate) latency: Option<Duration>,
+    pub(crate) peer: SocketAddr,
+    pub(crate) connected_at: Instant,
+    //the presence status, "online" until the connection sets another one
+    pub(crate) status: String,
+    //updated by the connection task
+    pub(crate) stats: Arc<Stats>,
+}
+
+//the counters of a connection, updated without locking the hub
+pub(crate) struct Stats {
+    pub(crate) messages_in: AtomicU64,
+    pub(crate) messages_out: AtomicU64,
+    pub(crate) last_activity: Mutex<Instant>,
+    //the client is away, the connection waits to be resumed
+    pub(crate) suspended: AtomicBool,
+}
+
+impl Stats {
+    pub(crate) fn new() -> Self {
+        Self {
+            messages_in: AtomicU64::new(0),
+            messages_out: AtomicU64::new(0),
+            last_activity: Mutex::new(Instant::now()),
+            suspended: AtomicBool::new(false),
+        }
+    }
+
+    //a message from the client
+    pub(crate) fn received(&self) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    //a message to the client
+    pub(crate) fn sent(&self) {
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Connection {
+    //suspended connections keep their entry (their messages are queued), but they aren't online
+    fn is_online(&self) -> bool {
+        !self.stats.suspended.load(Ordering::Relaxed)
+    }
+}
+
+//the connected clients and the rooms, shared by the server and every dispatcher
+pub(crate) struct Hub {
+    pub(crate) connections: Mutex<HashMap<ConnectionId, Connection>>,
+    pub(crate) rooms: Mutex<Rooms>,
+    //the id of this instance on the backplane
+    pub(crate) node: String,
+    pub(crate) backplane: RwLock<Option<Arc<dyn Backplane>>>,
+    pub(crate) presence: RwLock<Option<Presence>>,
+    //only one task delivers the messages of the backplane, even if the server is served on several listeners
+    subscribed: AtomicBool,
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self {
+            connections: Mutex::new(HashMap::new()),
+            rooms: Mutex::new(Rooms::default()),
+            node: Uuid::new_v4().simple().to_string(),
+            backplane: RwLock::new(None),
+            presence: RwLock::new(None),
+            subscribed: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Hub {
+    //queues the message for its recipients, without waiting for full queues
+    //room sends and broadcasts are published on the backplane too, for the clients of the other instances
+    pub(crate) fn deliver(&self, msg: GlobalDisp) {
+        if let GlobalDisp::To { to, msg } = msg {
+            let _ = self.send_to(&to, msg);
+            return;
+        }
+        self.publish(&msg);
+        self.deliver_local(msg);
+    }
+
+    //queues the message for the clients of this instance
+    //the message is converted to Utf8Bytes once, every recipient gets a cheap clone of the same bytes
+    pub(crate) fn deliver_local(&self, msg: GlobalDisp) {
+        match msg {
+            GlobalDisp::To { to, msg } => {
+                if let Some(connection) = self.connections.lock().unwrap().get(&to) {
+                    let _ = connection.outbox.push(msg);
+                }
+            }
+            GlobalDisp::Room { room, msg } => {
+                let members = self.rooms.lock().unwrap().members(&room);
+                let connections = self.connections.lock().unwrap();
+                for member in members {
+                    if let Some(connection) = connections.get(&member) {
+                        let _ = connection.outbox.push(msg.clone());
+                    }
+                }
+            }
+            GlobalDisp::Rooms { rooms, msg } => {
+                let members: HashSet<ConnectionId> = {
+                    let all = self.rooms.lock().unwrap();
+                    rooms.iter().flat_map(|room| all.members(room)).collect()
+                };
+                let connections = self.connections.lock().unwrap();
+                for member in members {
+                    if let Some(connection) = connections.get(&member) {
+                        let _ = connection.outbox.push(msg.clone());
+                    }
+                }
+            }
+            GlobalDisp::Broadcast { msg, except } => {
+                for (id, connection) in self.connections.lock().unwrap().iter() {
+                    if except.as_ref() != Some(id) {
+                        let _ = connection.outbox.push(msg.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    //queues the message for one connection
+    //with a backplane, the messages for unknown ids are published (the result can't tell if they arrive)
+    pub(crate) fn send_to(&self, to: &ConnectionId, msg: Utf8Bytes) -> Result<(), SendError> {
+        if let Some(connection) = self.connections.lock().unwrap().get(to) {
+            return connection.outbox.push(msg);
+        }
+        let msg = GlobalDisp::To { to: to.clone(), msg };
+        if self.publish(&msg) {
+            Ok(())
+        } else {
+            Err(SendError::UnknownConnection)
+        }
+    }
+
+    //publishes the message for the other instances, false without a backplane
+    fn publish(&self, msg: &GlobalDisp) -> bool {
+        let Some(backplane) = self.backplane.read().unwrap().clone() else {
+            return false;
+        };
+        let (target, msg) = match msg {
+            GlobalDisp::To { to, msg } => (Target::Connection(to.clone()), msg),
+            GlobalDisp::Room { room, msg } => (Target::Room(room.clone()), msg),
+            GlobalDisp::Rooms { rooms, msg } => (Target::Rooms(rooms.clone()), msg),
+            GlobalDisp::Broadcast { msg, except } => (Target::Broadcast { except: except.clone() }, msg),
+        };
+        backplane.publish(BackplaneMessage {
+            origin: self.node.clone(),
+            target,
+            msg: msg.to_string(),
+        });
+        true
+    }
+
+    //delivers the messages the other instances published until the server stops
+    //the errors of the backplane are reported, if its stream ends the hub subscribes again after a while
+    pub(crate) async fn subscribe<F: Future<Output = ()>>(
+        &self,
+        stopping: impl Future<Output = ()>,
+        report: impl Fn(BackplaneError) -> F,
+    ) {
+        let Some(backplane) = self.backplane.read().unwrap().clone() else {
+            return;
+        };
+        if self.subscribed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        tokio::pin!(stopping);
+        let mut retry = SUBSCRIBE_RETRY;
+        'subscribe: loop {
+            let mut messages = backplane.subscribe();
+            loop {
+                let msg = tokio::select! {
+                    msg = messages.next() => match msg {
+                        Some(Ok(msg)) => msg,
+                        Some(Err(e)) => {
+                            report(e).await;
+                            continue;
+                        }
+                        None => break,
+                    },
+                    _ = &mut stopping => break 'subscribe,
+                };
+                retry = SUBSCRIBE_RETRY;
+                if msg.origin == self.node {
+                    continue;
+                }
+                let msg = match msg.target {
+                    Target::Connection(to) => GlobalDisp::To { to, msg: msg.msg.into() },
+                    Target::Room(room) => GlobalDisp::Room { room, msg: msg.msg.into() },
+                    Target::Rooms(rooms) => GlobalDisp::Rooms { rooms, msg: msg.msg.into() },
+                    Target::Broadcast { except } => GlobalDisp::Broadcast {
+                        msg: msg.msg.into(),
+                        except,
+                    },
+                };
+                self.deliver_local(msg);
+            }
+            report("the backplane subscription ended".into()).await;
+            tokio::select! {
+                _ = tokio::time::sleep(retry) => {},
+                _ = &mut stopping => break,
+            }
+            retry = (retry * 2).min(SUBSCRIBE_MAX_RETRY);
+        }
+        self.subscribed.store(false, Ordering::SeqCst);
+    }
+
+    //the ids of the connected clients
+    pub(crate) fn ids(&self) -> Vec<ConnectionId> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, connection)| connection.is_online())
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|connection| connection.is_online())
+            .count()
+    }
+
+    pub(crate) fn is_online(&self, id: &ConnectionId) -> bool {
+        self.connections.lock().unwrap().get(id).is_some_and(Connection::is_online)
+    }
+
+    //a snapshot of the connection, None if it isn't connected
+    pub(crate) fn info(&self, id: &ConnectionId) -> Option<ConnectionInfo> {
+        let connections = self.connections.lock().unwrap();
+        let connection = connections.get(id).filter(|connection| connection.is_online())?;
+        Some(ConnectionInfo {
+            id: id.clone(),
+            peer: connection.peer,
+            connected_at: connection.connected_at.into_std(),
+            last_activity: connection.stats.last_activity.lock().unwrap().into_std(),
+            messages_in: connection.stats.messages_in.load(Ordering::Relaxed),
+            messages_out: connection.stats.messages_out.load(Ordering::Relaxed),
+            latency: connection.latency,
+            status: connection.status.clone(),
+        })
+    }
+
+    //the presence status of a connected client
+    pub(crate) fn status(&self, id: &ConnectionId) -> Option<String> {
+        let connections = self.connections.lock().unwrap();
+        let connection = connections.get(id).filter(|connection| connection.is_online())?;
+        Some(connection.status.clone())
+    }
+
+    //changes the presence status of the connection, the watchers and the rooms get a status event
+    pub(crate) fn set_status(&self, id: &ConnectionId, status: String) {
+        match self.connections.lock().unwrap().get_mut(id) {
+            Some(connection) => connection.status = status,
+            None => return,
+        }
+        let rooms = self.rooms.lock().unwrap().rooms_of(id);
+        self.announce(id, PresenceEvent::Status, &rooms);
+    }
+
+    //sends the presence event of the connection (without presence tracking, nothing happens)
+    //connected, disconnected and status events go to the watchers; with room events, joined and left go to the room,
+    //disconnected and status to the rooms of the connection
+    pub(crate) fn announce(&self, id: &ConnectionId, event: PresenceEvent, rooms: &[String]) {
+        let Some(presence) = self.presence.read().unwrap().clone() else {
+            return;
+        };
+        let status = match self.connections.lock().unwrap().get(id) {
+            Some(connection) => connection.status.clone(),
+            None => "offline".to_string(),
+        };
+        let msg = presence.message(&event, id, &status);
+        let mut targets = Vec::new();
+        if let PresenceEvent::Connected | PresenceEvent::Disconnected | PresenceEvent::Status = event {
+            targets.extend(presence.watchers.clone());
+        }
+        if presence.room_events {
+            match &event {
+                PresenceEvent::Joined(room) | PresenceEvent::Left(room) => targets.push(room.clone()),
+                PresenceEvent::Disconnected | PresenceEvent::Status => targets.extend(rooms.iter().cloned()),
+                PresenceEvent::Connected => {}
+            }
+        }
+        //one message for all the rooms, a client that is in several of them (e.g. a watcher in the same room)
+        //gets the event once
+        targets.sort();
+        targets.dedup();
+        if !targets.is_empty() {
+            self.deliver(GlobalDisp::Rooms {
+                rooms: targets,
+                msg: msg.as_str().into(),
+            });
+        }
+    }
+
+    //asks the connection task of the connection to close it
+    pub(crate) fn close(&self, id: &ConnectionId, frame: CloseFrame) -> Result<(), SendError> {
+        match self.connections.lock().unwrap().get(id) {
+            Some(connection) => {
+                connection.outbox.request_close(frame, DisconnectKind::Kicked);
+                Ok(())
+            }
+            None => Err(SendError::UnknownConnection),
+        }
+    }
+}
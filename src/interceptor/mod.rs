@@ -1,7 +1,5 @@
-use std::pin::Pin;
 use std::sync::Arc;
-use uuid::Uuid;
-use crate::routes::{Dispatcher, State};
+use crate::routes::{BoxFuture, ConnectionId, State};
 
 //The interceptor can modify the raw incoming msg without processing it (could be)
 //IMPORTANT: the interceptor can't modify ws! it receives a raw string and can process it
@@ -23,20 +21,20 @@ pub enum InterceptorType {
 
 pub struct ServerInterceptor<S> {
     pub r#type: InterceptorType,
-    pub callback: Arc<dyn Fn(String, Uuid, State<S>) -> Pin<Box<dyn Future<Output=InterceptorResult> + Send>> + Send + Sync + 'static>
+    pub callback: Arc<dyn Fn(String, ConnectionId, State<S>) -> BoxFuture<InterceptorResult> + Send + Sync + 'static>
 }
 
 impl<S> ServerInterceptor<S> {
     pub fn new<F,Fut>(callback: F, r#type: InterceptorType) -> Self
-    where F: Fn(String, Uuid, State<S>) -> Fut + Send + Sync + 'static,
+    where F: Fn(String, ConnectionId, State<S>) -> Fut + Send + Sync + 'static,
         Fut: Future<Output=InterceptorResult> + Send + 'static {
-        ServerInterceptor { r#type, callback: Arc::new(move |incoming, uuid, state| {Box::pin(callback(incoming, uuid, state))}) }
+        ServerInterceptor { r#type, callback: Arc::new(move |incoming, id, state| {Box::pin(callback(incoming, id, state))}) }
     }
 }
 
 pub struct Interceptor<S> {
     pub r#type: InterceptorType,
-    pub callback: Arc<dyn Fn(String, State<S>) -> Pin<Box<dyn Future<Output=InterceptorResult> + Send>> + Send + Sync + 'static>
+    pub callback: Arc<dyn Fn(String, State<S>) -> BoxFuture<InterceptorResult> + Send + Sync + 'static>
 }
 
 impl<S> Interceptor<S> {
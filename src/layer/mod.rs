@@ -1,6 +1,6 @@
-use std::pin::Pin;
 use std::sync::Arc;
-use crate::routes::{Dispatcher, Params, State};
+use crate::routes::{BoxFuture, Dispatcher, Params, State};
+#[cfg(feature = "server")]
 use crate::routes::ServerDispatcher;
 
 pub enum LayerResult {
@@ -12,7 +12,7 @@ pub struct ClientLayer<S> {
     pub name: String,
     pub(crate) allowed: Vec<String>,
     pub(crate) blocked: Vec<String>,
-    pub callback: Arc<dyn Fn(Params, Dispatcher, State<S>) -> Pin<Box<dyn Future<Output=LayerResult> + Send>> + Send + Sync + 'static>
+    pub callback: Arc<dyn Fn(Params, Dispatcher, State<S>) -> BoxFuture<LayerResult> + Send + Sync + 'static>
 }
 
 impl<S> Clone for ClientLayer<S> {
@@ -51,13 +51,15 @@ impl<S> ClientLayer<S> {
 }
 
 
+#[cfg(feature = "server")]
 pub struct ServerLayer<S> {
     pub name: String,
     pub(crate) allowed: Vec<String>,
     pub(crate) blocked: Vec<String>,
-    pub callback: Arc<dyn Fn(Params, ServerDispatcher, State<S>) -> Pin<Box<dyn Future<Output=LayerResult> + Send>> + Send + Sync + 'static>
+    pub callback: Arc<dyn Fn(Params, ServerDispatcher, State<S>) -> BoxFuture<LayerResult> + Send + Sync + 'static>
 }
 
+#[cfg(feature = "server")]
 impl<S> Clone for ServerLayer<S> {
     fn clone(&self) -> Self {
         ServerLayer {
@@ -69,6 +71,7 @@ impl<S> Clone for ServerLayer<S> {
     }
 }
 
+#[cfg(feature = "server")]
 impl<S> ServerLayer<S> {
     pub fn new<F, Fut>(name: impl Into<String>, callback: F) -> ServerLayer<S>
     where
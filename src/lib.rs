@@ -10,16 +10,59 @@ pub mod interceptor;
 #[cfg(feature = "layers")]
 pub mod layer;
 pub mod command;
+#[cfg(feature = "server")]
+pub mod admission;
+#[cfg(any(feature = "server", feature = "client"))]
+pub mod error;
+pub mod handler;
+#[cfg(feature = "server")]
+pub mod request;
+#[cfg(feature = "server")]
+mod room;
+#[cfg(feature = "server")]
+mod hub;
+#[cfg(feature = "server")]
+pub mod queue;
+#[cfg(feature = "server")]
+pub mod session;
 mod parser;
+#[cfg(feature = "server")]
+pub mod ratelimit;
+#[cfg(feature = "server")]
+pub mod router;
+#[cfg(feature = "server")]
+pub mod handle;
+#[cfg(feature = "server")]
+pub mod backplane;
+#[cfg(feature = "server")]
+pub mod presence;
+#[cfg(any(feature = "server", feature = "client"))]
+pub mod resume;
+#[cfg(any(feature = "server", feature = "client"))]
+pub mod disconnect;
+#[cfg(feature = "hyper")]
+pub mod embed;
+#[cfg(all(feature = "deflate", any(feature = "server", feature = "client")))]
+mod deflate;
 
 
-#[cfg(all(test))]
+#[cfg(test)]
 mod tests {
     use crate::command::Command;
     use crate::parser::Parsed;
     use crate::routes::Params;
 
+    //a listener on a free port for a test server, bound before the server is spawned
+    //so the clients can connect right away
+    #[cfg(feature = "server")]
+    async fn listen() -> (tokio::net::TcpListener, String) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        (listener, addr)
+    }
+
     #[test]
+    #[allow(unused_variables)]
     fn test_parser() {
         println!("IM HERE!!");
         let text = Command::from("JUHUU", Params::from([("asd".to_string(),"'pulu-lulu'".to_string())]) );
@@ -31,7 +74,1788 @@ mod tests {
         println!("ENDED")
     }
 
+    #[cfg(all(feature = "server", feature = "client"))]
+    #[tokio::test]
+    async fn test_struct_handler() {
+        use crate::client::Connector;
+        use crate::handler::{Context, Handler};
+        use crate::routes::{Dispatcher, ServerDispatcher};
+        use crate::server::Server;
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tokio::sync::mpsc::UnboundedSender;
 
+        struct Greeter {
+            greeting: String,
+        }
 
-}
+        impl Handler<(), ServerDispatcher> for Greeter {
+            async fn call(&self, ctx: Context<(), ServerDispatcher>) {
+                let name = ctx.params.get("name").unwrap();
+                ctx.dispatcher.send(format!("{} #name {}", self.greeting, name));
+            }
+        }
+
+        struct Collector {
+            prefix: &'static str,
+        }
+
+        impl Handler<UnboundedSender<String>, Dispatcher> for Collector {
+            async fn call(&self, ctx: Context<UnboundedSender<String>, Dispatcher>) {
+                let _ = ctx.state.send(format!("{} {}", self.prefix, ctx.params.get("name").unwrap()));
+            }
+        }
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server
+            .route_handler("@GREET", Greeter { greeting: "@HELLO".to_string() })
+            .await;
+        server
+            .route_handler("@WAVE", Greeter { greeting: "@HI".to_string() })
+            .await;
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let (collected_tx, mut collected_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut connector = Connector::new(format!("ws://{}", addr), collected_tx);
+        connector.route("CONNECTED", |_params, dispatcher, _state| async move {
+            dispatcher.send("@GREET #name bob");
+        });
+        //one handler shared by two routes
+        let collector = Arc::new(Collector { prefix: "hello" });
+        connector.route_handler("@HELLO", collector.clone());
+        connector.route_handler("@HI", collector);
+        let dispatcher = connector.connect().await;
+
+        let collected = tokio::time::timeout(Duration::from_secs(2), collected_rx.recv()).await.unwrap();
+        assert_eq!(collected.unwrap(), "hello bob");
+        dispatcher.send("@WAVE #name alice");
+        let collected = tokio::time::timeout(Duration::from_secs(2), collected_rx.recv()).await.unwrap();
+        assert_eq!(collected.unwrap(), "hello alice");
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        use crate::server::Server;
+        use futures_util::StreamExt;
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server.goodbye("@BYE");
+        server.grace_period(Duration::from_millis(200));
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let (listener, addr) = listen().await;
+        let serving = tokio::spawn(async move {
+            server
+                .serve_listener_with_shutdown(listener, async {
+                    let _ = stop_rx.await;
+                })
+                .await
+                .unwrap();
+        });
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        stop_tx.send(()).unwrap();
+
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::text("@BYE"));
+        assert!(matches!(ws.next().await.unwrap().unwrap(), Message::Close(Some(_))));
+        tokio::time::timeout(Duration::from_secs(1), serving).await.unwrap().unwrap();
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_server_errors() {
+        use crate::error::ServerError;
+        use crate::server::Server;
+        use tokio::io::AsyncWriteExt;
+
+        //the port is already in use
+        let busy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = Server::new(busy.local_addr().unwrap().to_string(), ());
+        assert!(matches!(server.serve().await, Err(ServerError::Bind { .. })));
+
+        //a client that doesn't speak websocket
+        let (error_tx, mut error_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut server = Server::new("127.0.0.1:0", ());
+        server.on_error(move |error, peer, _state| {
+            let error_tx = error_tx.clone();
+            async move {
+                let _ = error_tx.send((error, peer));
+            }
+        });
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let mut stream = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        stream.write_all(b"hello\r\n\r\n").await.unwrap();
+        let (error, peer) = error_rx.recv().await.unwrap();
+        assert!(matches!(error, ServerError::Handshake(_)));
+        assert_eq!(peer, stream.local_addr().unwrap());
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[tokio::test]
+    async fn test_tls_server() {
+        use crate::server::{Identity, Server};
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let identity = Identity::from_pkcs8(
+            certified.cert.pem().as_bytes(),
+            certified.signing_key.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server.with_tls(identity);
+        server
+            .route("CONNECTED", |_params, dispatcher, _state| async move {
+                dispatcher.send("@WELCOME");
+            })
+            .await;
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let (mut ws, _) = tokio_tungstenite::connect_async_tls_with_config(
+            format!("wss://{}", addr.replace("127.0.0.1", "localhost")),
+            None,
+            false,
+            Some(tokio_tungstenite::Connector::NativeTls(connector)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::text("@WELCOME"));
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[tokio::test]
+    async fn test_tls_handshake_stall() {
+        use crate::error::ServerError;
+        use crate::server::{Identity, Server};
+        use std::time::Duration;
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let identity = Identity::from_pkcs8(
+            certified.cert.pem().as_bytes(),
+            certified.signing_key.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        let (error_tx, mut error_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut server = Server::new("127.0.0.1:0", ());
+        server.with_tls(identity);
+        server.grace_period(Duration::from_millis(100));
+        server.handshake_timeout(Duration::from_millis(300));
+        server.on_error(move |error, _peer, _state| {
+            let error_tx = error_tx.clone();
+            async move {
+                let _ = error_tx.send(error);
+            }
+        });
+        let handle = server.handle();
+        let (listener, addr) = listen().await;
+        let serving = tokio::spawn(async move { server.serve_listener(listener).await });
+
+        //connections that never start the tls handshake
+        let _stalled = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        let error = tokio::time::timeout(Duration::from_secs(2), error_rx.recv()).await.unwrap();
+        assert!(matches!(error, Some(ServerError::HandshakeTimeout)));
+
+        //they don't keep the shutdown waiting
+        let _stalled = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        handle.shutdown();
+        let result = tokio::time::timeout(Duration::from_secs(1), serving).await.unwrap();
+        assert!(result.unwrap().is_ok());
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_rooms() {
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server
+            .route("@JOIN", |_params, dispatcher, _state| async move {
+                dispatcher.join("lobby");
+                dispatcher.send("@JOINED");
+            })
+            .await;
+        server
+            .route("@SAY", |_params, dispatcher, _state| async move {
+                dispatcher.broadcast_to_room("lobby", "@MSG");
+            })
+            .await;
+        server
+            .route("@COUNT", |_params, dispatcher, _state| async move {
+                let count = dispatcher.room_members("lobby").len();
+                dispatcher.send(format!("@COUNT #count {}", count));
+            })
+            .await;
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let url = &format!("ws://{}", addr);
+        let (mut a, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let (mut b, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let (mut outsider, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        for ws in [&mut a, &mut b] {
+            ws.send(Message::text("@JOIN")).await.unwrap();
+            assert_eq!(ws.next().await.unwrap().unwrap(), Message::text("@JOINED"));
+        }
+
+        //only the members get the broadcast
+        outsider.send(Message::text("@SAY")).await.unwrap();
+        assert_eq!(a.next().await.unwrap().unwrap(), Message::text("@MSG"));
+        assert_eq!(b.next().await.unwrap().unwrap(), Message::text("@MSG"));
+        outsider.send(Message::text("@COUNT")).await.unwrap();
+        assert_eq!(outsider.next().await.unwrap().unwrap(), Message::text("@COUNT #count 2"));
+
+        //disconnecting leaves the room
+        a.close(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        outsider.send(Message::text("@COUNT")).await.unwrap();
+        assert_eq!(outsider.next().await.unwrap().unwrap(), Message::text("@COUNT #count 1"));
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_broadcast() {
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server
+            .route("@ALL", |_params, dispatcher, _state| async move {
+                dispatcher.broadcast("@TO-ALL");
+            })
+            .await;
+        server
+            .route("@OTHERS", |_params, dispatcher, _state| async move {
+                dispatcher.broadcast_except_self("@TO-OTHERS");
+            })
+            .await;
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let url = &format!("ws://{}", addr);
+        let (mut a, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let (mut b, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        a.send(Message::text("@OTHERS")).await.unwrap();
+        assert_eq!(b.next().await.unwrap().unwrap(), Message::text("@TO-OTHERS"));
+        a.send(Message::text("@ALL")).await.unwrap();
+        //the sender skipped @TO-OTHERS, so the next message is @TO-ALL
+        assert_eq!(a.next().await.unwrap().unwrap(), Message::text("@TO-ALL"));
+        assert_eq!(b.next().await.unwrap().unwrap(), Message::text("@TO-ALL"));
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_session() {
+        use crate::layer::{LayerResult, ServerLayer};
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        #[derive(Clone)]
+        struct User(String);
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server.layer(
+            ServerLayer::new("AUTH", |params: Params, dispatcher: crate::routes::ServerDispatcher, _state| async move {
+                if let Some(name) = params.get("name") {
+                    dispatcher.session().insert(User(name.clone()));
+                }
+                LayerResult::Pass(params)
+            }),
+        );
+        server
+            .route("@LOGIN", |_params, dispatcher, _state| async move {
+                dispatcher.send("@LOGGED-IN");
+            })
+            .await;
+        server
+            .route("@WHOAMI", |_params, dispatcher, _state| async move {
+                match dispatcher.session().get::<User>() {
+                    Some(User(name)) => dispatcher.send(format!("@USER #name {}", name)),
+                    None => dispatcher.send("@ANONYMOUS"),
+                }
+            })
+            .await;
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let url = &format!("ws://{}", addr);
+        let (mut a, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let (mut b, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        a.send(Message::text("@LOGIN #name alice")).await.unwrap();
+        assert_eq!(a.next().await.unwrap().unwrap(), Message::text("@LOGGED-IN"));
 
+        a.send(Message::text("@WHOAMI")).await.unwrap();
+        assert_eq!(a.next().await.unwrap().unwrap(), Message::text("@USER #name alice"));
+        b.send(Message::text("@WHOAMI")).await.unwrap();
+        assert_eq!(b.next().await.unwrap().unwrap(), Message::text("@ANONYMOUS"));
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_heartbeat() {
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use std::time::Duration;
+        use tokio::sync::mpsc::UnboundedSender;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let (reason_tx, mut reason_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+        let mut server = Server::new("127.0.0.1:0", reason_tx);
+        server.heartbeat(Duration::from_millis(50), Duration::from_millis(100));
+        server
+            .route("@LATENCY", |_params, dispatcher, _state| async move {
+                //waits for the first answered ping
+                for _ in 0..50 {
+                    if dispatcher.latency().is_some() {
+                        dispatcher.send("@MEASURED");
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            })
+            .await;
+        server
+            .route("DISCONNECTED", |params, _dispatcher, state: crate::routes::State<UnboundedSender<String>>| async move {
+                let _ = state.send(params.get("reason").unwrap().clone());
+            })
+            .await;
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        //this client answers the pings while it waits for the reply
+        let url = &format!("ws://{}", addr);
+        let (mut alive, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        alive.send(Message::text("@LATENCY")).await.unwrap();
+        let reply = loop {
+            match alive.next().await.unwrap().unwrap() {
+                Message::Ping(_) => continue,
+                msg => break msg,
+            }
+        };
+        assert_eq!(reply, Message::text("@MEASURED"));
+
+        //this one never reads, so it never answers
+        let (_dead, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let reason = tokio::time::timeout(Duration::from_secs(2), reason_rx.recv()).await.unwrap();
+        assert_eq!(reason.unwrap(), "ping timeout");
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_admission() {
+        use crate::admission::{Admission, StatusCode};
+        use crate::server::Server;
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Error;
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server.max_connections_per_ip(1);
+        server.admission(|request, _state| {
+            if request.path == "/banned" {
+                Admission::Reject(StatusCode::FORBIDDEN)
+            } else {
+                Admission::Accept
+            }
+        });
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let status = |result: Result<_, Error>| match result {
+            Err(Error::Http(response)) => response.status(),
+            _ => panic!("the upgrade should be rejected"),
+        };
+        let rejected = tokio_tungstenite::connect_async(format!("ws://{}/banned", addr)).await;
+        assert_eq!(status(rejected), StatusCode::FORBIDDEN);
+
+        //the rejected upgrade didn't take the place of the ip
+        let (first, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        let second = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await;
+        assert_eq!(status(second), StatusCode::TOO_MANY_REQUESTS);
+
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.is_ok());
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_rate_limit() {
+        use crate::ratelimit::{RateLimit, RateLimitPolicy};
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server.rate_limit(
+            RateLimit::new(1, Duration::from_secs(10))
+                .burst(2)
+                .policy(RateLimitPolicy::Reply),
+        );
+        server.route_rate_limit(
+            "@CHAT",
+            RateLimit::new(1, Duration::from_secs(10)).policy(RateLimitPolicy::Disconnect),
+        );
+        server
+            .route("@PING", |_params, dispatcher, _state| async move {
+                dispatcher.send("@PONG");
+            })
+            .await;
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let url = &format!("ws://{}", addr);
+        let (mut flooding, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        for _ in 0..3 {
+            flooding.send(Message::text("@PING")).await.unwrap();
+        }
+        let mut replies = Vec::new();
+        for _ in 0..3 {
+            replies.push(flooding.next().await.unwrap().unwrap().into_text().unwrap().to_string());
+        }
+        replies.sort();
+        assert_eq!(replies, ["@PONG", "@PONG", "@RATE-LIMITED"]);
+
+        let (mut chatting, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        chatting.send(Message::text("@CHAT")).await.unwrap();
+        chatting.send(Message::text("@CHAT")).await.unwrap();
+        assert!(matches!(chatting.next().await.unwrap().unwrap(), Message::Close(Some(_))));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_rate_limit_zero() {
+        use crate::ratelimit::RateLimit;
+        use std::time::Duration;
+
+        let zero_per = std::panic::catch_unwind(|| RateLimit::new(10, Duration::ZERO));
+        assert!(zero_per.is_err());
+        let zero_rate = std::panic::catch_unwind(|| RateLimit::new(0, Duration::from_secs(1)));
+        assert!(zero_rate.is_err());
+        let zero_burst =
+            std::panic::catch_unwind(|| RateLimit::new(10, Duration::from_secs(1)).burst(0));
+        assert!(zero_burst.is_err());
+        RateLimit::new(10, Duration::from_secs(1)).burst(1);
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_upgrade_request() {
+        use crate::server::Server;
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::Message;
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server
+            .route("CONNECTED", |_params, dispatcher, _state| async move {
+                let request = dispatcher.request();
+                dispatcher.send(format!(
+                    "@HELLO #path {} #token '{}' #session {} #local {}",
+                    request.path,
+                    request.query("token").unwrap(),
+                    request.cookie("session").unwrap(),
+                    request.peer.ip().is_loopback(),
+                ));
+            })
+            .await;
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let mut request = format!("ws://{}/chat?token=a%20b&x=1", addr)
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert("cookie", "theme=dark; session=s3cr3t".parse().unwrap());
+        let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!(
+            ws.next().await.unwrap().unwrap(),
+            Message::text("@HELLO #path /chat #token 'a b' #session s3cr3t #local true")
+        );
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_mount() {
+        use crate::router::Router;
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut chat = Router::new("chat".to_string());
+        chat.route("@WHO", |_params, dispatcher, state| async move {
+            dispatcher.send(format!("@WHO #endpoint {}", state));
+        });
+        let mut admin = Router::new(42u32);
+        admin.route("@WHO", |_params, dispatcher, state| async move {
+            dispatcher.send(format!("@WHO #admin {}", state));
+        });
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server
+            .route("@WHO", |_params, dispatcher, _state| async move {
+                dispatcher.send("@WHO #root");
+            })
+            .await;
+        server.mount("/chat", chat);
+        server.mount("admin", admin);
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/chat", addr)).await.unwrap();
+        ws.send(Message::text("@WHO")).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::text("@WHO #endpoint chat"));
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/admin/", addr)).await.unwrap();
+        ws.send(Message::text("@WHO")).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::text("@WHO #admin 42"));
+
+        //the routes of the server are on "/"
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        ws.send(Message::text("@WHO")).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::text("@WHO #root"));
+
+        let unknown = tokio_tungstenite::connect_async(format!("ws://{}/other", addr)).await;
+        assert!(matches!(
+            unknown,
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) if response.status() == 404
+        ));
+    }
+
+    #[cfg(feature = "axum")]
+    #[tokio::test]
+    async fn test_axum() {
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use std::net::SocketAddr;
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut server = Server::new("", ());
+        server
+            .route("CONNECTED", |_params, dispatcher, _state| async move {
+                let local = dispatcher.request().peer.ip().is_loopback();
+                dispatcher.send(format!("@HELLO #local {}", local));
+            })
+            .await;
+        server
+            .route("@PING", |_params, dispatcher, _state| async move {
+                dispatcher.send("@PONG");
+            })
+            .await;
+        let upgrader = server.upgrader();
+
+        let app = axum::Router::new()
+            .route("/health", axum::routing::get(|| async { "ok" }))
+            .route_service("/ws", upgrader.clone());
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await
+        });
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::text("@HELLO #local true"));
+        ws.send(Message::text("@PING")).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::text("@PONG"));
+
+        tokio::time::timeout(Duration::from_secs(1), upgrader.shutdown()).await.unwrap();
+        assert!(matches!(ws.next().await.unwrap().unwrap(), Message::Close(Some(_))));
+        assert!(tokio_tungstenite::connect_async(format!("ws://{}/ws", addr)).await.is_err());
+    }
+
+    #[cfg(all(unix, feature = "server", feature = "client"))]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use crate::client::Connector;
+        use crate::server::Server;
+        use std::time::Duration;
+
+        let path = std::env::temp_dir().join(format!("wroustr-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut server = Server::new("", ());
+        server
+            .route("@PING", |_params, dispatcher, _state| async move {
+                dispatcher.send(format!("@PONG #path {}", dispatcher.request().path));
+            })
+            .await;
+        let socket = path.clone();
+        tokio::spawn(async move { server.serve_unix(socket).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (pong_tx, mut pong_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut connector = Connector::new("ws://localhost/sidecar", pong_tx);
+        connector.unix_socket(&path);
+        connector.route("CONNECTED", |_params, dispatcher, _state| async move {
+            dispatcher.send("@PING");
+        });
+        connector.route("@PONG", |params, _dispatcher, state| async move {
+            let _ = state.send(params.get("path").cloned().unwrap());
+        });
+        let _dispatcher = connector.connect().await;
+
+        let path_param = tokio::time::timeout(Duration::from_secs(2), pong_rx.recv()).await.unwrap();
+        assert_eq!(path_param.unwrap(), "/sidecar");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_serve_incoming() {
+        use crate::error::ServerError;
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use std::io::{Error, ErrorKind};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let (error_tx, mut error_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut server = Server::new("", ());
+        server
+            .route("@PING", |_params, dispatcher, _state| async move {
+                dispatcher.send("@PONG");
+            })
+            .await;
+        server.on_error(move |error, _peer, _state| {
+            let error_tx = error_tx.clone();
+            async move {
+                let _ = error_tx.send(error);
+            }
+        });
+        let (streams_tx, streams_rx) = tokio::sync::mpsc::unbounded_channel();
+        let incoming = futures_util::stream::unfold(streams_rx, |mut streams_rx| async move {
+            let stream = streams_rx.recv().await?;
+            Some((stream, streams_rx))
+        });
+        let serving = tokio::spawn(async move { server.serve_incoming(incoming).await });
+
+        //a failed accept is reported, the server keeps running
+        streams_tx.send(Err(Error::from(ErrorKind::ConnectionAborted))).unwrap();
+        assert!(matches!(error_rx.recv().await, Some(ServerError::Io(_))));
+        //running out of file descriptors too
+        #[cfg(unix)]
+        {
+            streams_tx.send(Err(Error::from_raw_os_error(libc::EMFILE))).unwrap();
+            assert!(matches!(error_rx.recv().await, Some(ServerError::Io(_))));
+        }
+
+        let (client, server_side) = tokio::io::duplex(4096);
+        streams_tx.send(Ok(server_side)).unwrap();
+        let (mut ws, _) = tokio_tungstenite::client_async("ws://localhost/", client).await.unwrap();
+        ws.send(Message::text("@PING")).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::text("@PONG"));
+
+        //the server shuts down when the stream ends
+        drop(streams_tx);
+        assert!(matches!(ws.next().await.unwrap().unwrap(), Message::Close(Some(_))));
+        assert!(serving.await.unwrap().is_ok());
+
+        //a broken listener stops the server with the error
+        let server = Server::new("", ());
+        let incoming = futures_util::stream::iter([Err::<tokio::io::DuplexStream, _>(Error::other("broken"))]);
+        assert!(matches!(server.serve_incoming(incoming).await, Err(ServerError::Io(_))));
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_connection_id() {
+        use crate::admission::Admission;
+        use crate::routes::ConnectionId;
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        let counter = AtomicU64::new(0);
+        server.connection_id(move |_request| {
+            ConnectionId::new(format!("conn-{}", counter.fetch_add(1, Ordering::Relaxed) + 1))
+        });
+        //logged in users get their user id
+        server.admission(|request, _state| match request.query("user") {
+            Some(user) => Admission::AcceptAs(ConnectionId::from(user)),
+            None => Admission::Accept,
+        });
+        server
+            .route("CONNECTED", |params, dispatcher, _state| async move {
+                assert_eq!(params.get("uuid").unwrap(), dispatcher.id().as_str());
+                dispatcher.send(format!("@ID #id {}", dispatcher.id()));
+            })
+            .await;
+        server
+            .route("@TELL", |params, dispatcher, _state| async move {
+                if let Err(e) = dispatcher.try_send_to("@TOLD", params.get("to").unwrap()) {
+                    dispatcher.send(format!("@ERR #error {}", e));
+                }
+            })
+            .await;
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let (mut anonymous, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        assert_eq!(anonymous.next().await.unwrap().unwrap(), Message::text("@ID #id conn-1"));
+        let (mut alice, _) = tokio_tungstenite::connect_async(format!("ws://{}/?user=alice", addr)).await.unwrap();
+        assert_eq!(alice.next().await.unwrap().unwrap(), Message::text("@ID #id alice"));
+
+        //the id is already connected
+        let duplicate = tokio_tungstenite::connect_async(format!("ws://{}/?user=alice", addr)).await;
+        assert!(matches!(
+            duplicate,
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) if response.status() == 409
+        ));
+
+        anonymous.send(Message::text("@TELL #to alice")).await.unwrap();
+        assert_eq!(alice.next().await.unwrap().unwrap(), Message::text("@TOLD"));
+        anonymous.send(Message::text("@TELL #to bob")).await.unwrap();
+        assert_eq!(anonymous.next().await.unwrap().unwrap(), Message::text("@ERR #error unknown connection"));
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_shared_listeners() {
+        use crate::admission::{Admission, StatusCode};
+        use crate::parser::Parsed;
+        use crate::routes::ConnectionId;
+        use crate::server::Server;
+        use futures_util::StreamExt;
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tokio::net::TcpListener;
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+        use tokio_tungstenite::tungstenite::{Error, Message};
+
+        let mut server = Server::new("", ());
+        server.max_connections(2);
+        server.session_resumption(Duration::from_secs(1));
+        server.admission(|request, _state| match request.query("user") {
+            Some(user) => Admission::AcceptAs(ConnectionId::from(user)),
+            None => Admission::Accept,
+        });
+        //one server on two listeners
+        let server = Arc::new(server);
+        let mut urls = Vec::new();
+        for _ in 0..2 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            urls.push(format!("ws://{}", listener.local_addr().unwrap()));
+            let incoming = futures_util::stream::unfold(listener, |listener| async move {
+                Some((listener.accept().await.map(|(stream, _)| stream), listener))
+            });
+            let server = server.clone();
+            tokio::spawn(async move { server.serve_incoming(incoming).await });
+        }
+
+        let status = |result: Result<_, Error>| match result {
+            Err(Error::Http(response)) => response.status(),
+            _ => panic!("the upgrade should be rejected"),
+        };
+        //the id is taken on the other listener
+        let (_alice, _) = tokio_tungstenite::connect_async(format!("{}/?user=alice", urls[0])).await.unwrap();
+        let duplicate = tokio_tungstenite::connect_async(format!("{}/?user=alice", urls[1])).await;
+        assert_eq!(status(duplicate), StatusCode::CONFLICT);
+
+        //the limit counts the connections of both listeners
+        let (mut bob, _) = tokio_tungstenite::connect_async(&urls[1]).await.unwrap();
+        let third = tokio_tungstenite::connect_async(&urls[0]).await;
+        assert_eq!(status(third), StatusCode::SERVICE_UNAVAILABLE);
+
+        //a session suspended on one listener is resumed on the other
+        let next = |message: Message| Parsed::parse(message.into_text().unwrap().to_string());
+        let token = next(bob.next().await.unwrap().unwrap()).params["token"].clone();
+        bob.close(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut request = urls[0].as_str().into_client_request().unwrap();
+        request.headers_mut().insert("x-wroustr-resume", token.parse().unwrap());
+        let (mut bob, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!(next(bob.next().await.unwrap().unwrap()).command, "@RESUME-TOKEN");
+        assert_eq!(server.handle().connection_count(), 2);
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_session_resumption() {
+        use crate::parser::Parsed;
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Message;
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let (disconnected_tx, mut disconnected_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut server = Server::new("127.0.0.1:0", disconnected_tx);
+        server.session_resumption(Duration::from_millis(500));
+        server
+            .route("CONNECTED", |params, dispatcher, _state| async move {
+                let resumed = params.contains_key("resumed");
+                dispatcher.send(format!("@HELLO #id {} #resumed {}", dispatcher.id(), resumed));
+            })
+            .await;
+        server
+            .route("@TELL", |params, dispatcher, _state| async move {
+                dispatcher.send_to(format!("@TOLD #n {}", params.get("n").unwrap()), params.get("to").unwrap());
+            })
+            .await;
+        server
+            .route("DISCONNECTED", |params, _dispatcher, state| async move {
+                let _ = state.send(params.get("reason").cloned().unwrap());
+            })
+            .await;
+        let handle = server.handle();
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let url = &format!("ws://{}", addr);
+        let next = |message: Message| Parsed::parse(message.into_text().unwrap().to_string());
+
+        let (mut alice, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let token = next(alice.next().await.unwrap().unwrap()).params["token"].clone();
+        let hello = next(alice.next().await.unwrap().unwrap());
+        let id = hello.params["id"].clone();
+        assert_eq!(hello.params["resumed"], "false");
+        let (mut bob, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        //alice is away, the messages to her are queued
+        alice.close(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for n in 1..=2 {
+            bob.send(Message::text(format!("@TELL #to {} #n {}", id, n))).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut request = url.into_client_request().unwrap();
+        request.headers_mut().insert("x-wroustr-resume", token.parse().unwrap());
+        let (mut alice, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!(alice.next().await.unwrap().unwrap(), Message::text("@TOLD #n 1"));
+        assert_eq!(alice.next().await.unwrap().unwrap(), Message::text("@TOLD #n 2"));
+        assert_eq!(next(alice.next().await.unwrap().unwrap()).command, "@RESUME-TOKEN");
+        let hello = next(alice.next().await.unwrap().unwrap());
+        assert_eq!(hello.params["id"], id);
+        assert_eq!(hello.params["resumed"], "true");
+        assert!(disconnected_rx.try_recv().is_err());
+        //connected since the first connect
+        assert!(handle.connection_info(id.as_str()).unwrap().connected_at.elapsed() >= Duration::from_millis(150));
+
+        //DISCONNECTED runs when the window expires
+        drop(alice);
+        let reason = tokio::time::timeout(Duration::from_secs(2), disconnected_rx.recv()).await.unwrap();
+        assert!(matches!(reason.unwrap().as_str(), "closed" | "error"));
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_resumption_limits() {
+        use crate::admission::Admission;
+        use crate::parser::Parsed;
+        use crate::routes::ConnectionId;
+        use crate::server::Server;
+        use futures_util::StreamExt;
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Message;
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server.session_resumption(Duration::from_millis(500));
+        server.resume_by_id();
+        server.max_connections_per_ip(1);
+        server.admission(|request, _state| match request.query("user") {
+            Some(user) => Admission::AcceptAs(ConnectionId::from(user)),
+            None => Admission::Accept,
+        });
+        server
+            .route("CONNECTED", |params, dispatcher, _state| async move {
+                dispatcher.send(format!("@HELLO #resumed {}", params.contains_key("resumed")));
+            })
+            .await;
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let url = &format!("ws://{}/?user=alice", addr);
+        let next = |message: Message| Parsed::parse(message.into_text().unwrap().to_string());
+
+        let (mut alice, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let token = next(alice.next().await.unwrap().unwrap()).params["token"].clone();
+        assert_eq!(next(alice.next().await.unwrap().unwrap()).params["resumed"], "false");
+        alice.close(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        //the resumed connection takes over the slot of the suspended one
+        let mut request = url.into_client_request().unwrap();
+        request.headers_mut().insert("x-wroustr-resume", token.parse().unwrap());
+        let (mut alice, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        next(alice.next().await.unwrap().unwrap());
+        assert_eq!(next(alice.next().await.unwrap().unwrap()).params["resumed"], "true");
+        alice.close(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        //with resume_by_id(), the same user without the token resumes too instead of getting a conflict
+        let (mut alice, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        next(alice.next().await.unwrap().unwrap());
+        assert_eq!(next(alice.next().await.unwrap().unwrap()).params["resumed"], "true");
+        drop(alice);
+
+        //the slot is freed once the window expires
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        let (mut bob, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        next(bob.next().await.unwrap().unwrap());
+        assert_eq!(next(bob.next().await.unwrap().unwrap()).params["resumed"], "false");
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_resumption_endpoint() {
+        use crate::admission::Admission;
+        use crate::parser::Parsed;
+        use crate::router::Router;
+        use crate::routes::ConnectionId;
+        use crate::server::Server;
+        use futures_util::StreamExt;
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Message;
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server.session_resumption(Duration::from_millis(500));
+        server.admission(|request, _state| match request.query("user") {
+            Some(user) => Admission::AcceptAs(ConnectionId::from(user)),
+            None => Admission::Accept,
+        });
+        for path in ["/chat", "/admin"] {
+            let mut router = Router::new(());
+            router.route("CONNECTED", |params, dispatcher, _state| async move {
+                dispatcher.send(format!("@HELLO #id {} #resumed {}", dispatcher.id(), params.contains_key("resumed")));
+            });
+            server.mount(path, router);
+        }
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let next = |message: Message| Parsed::parse(message.into_text().unwrap().to_string());
+        let (mut alice, _) = tokio_tungstenite::connect_async(format!("ws://{}/chat", addr)).await.unwrap();
+        let token = next(alice.next().await.unwrap().unwrap()).params["token"].clone();
+        let id = next(alice.next().await.unwrap().unwrap()).params["id"].clone();
+        alice.close(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        //the token doesn't resume the session on another endpoint
+        let mut request = format!("ws://{}/admin", addr).into_client_request().unwrap();
+        request.headers_mut().insert("x-wroustr-resume", token.parse().unwrap());
+        let (mut admin, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        next(admin.next().await.unwrap().unwrap());
+        let hello = next(admin.next().await.unwrap().unwrap());
+        assert_ne!(hello.params["id"], id);
+        assert_eq!(hello.params["resumed"], "false");
+
+        //but still on its own
+        let mut request = format!("ws://{}/chat", addr).into_client_request().unwrap();
+        request.headers_mut().insert("x-wroustr-resume", token.parse().unwrap());
+        let (mut alice, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        next(alice.next().await.unwrap().unwrap());
+        let hello = next(alice.next().await.unwrap().unwrap());
+        assert_eq!((hello.params["id"].as_str(), hello.params["resumed"].as_str()), (id.as_str(), "true"));
+
+        //without resume_by_id(), the id of a suspended connection doesn't resume it
+        let (mut bob, _) = tokio_tungstenite::connect_async(format!("ws://{}/chat?user=bob", addr)).await.unwrap();
+        next(bob.next().await.unwrap().unwrap());
+        next(bob.next().await.unwrap().unwrap());
+        bob.close(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let bob = tokio_tungstenite::connect_async(format!("ws://{}/chat?user=bob", addr)).await;
+        assert!(matches!(
+            bob,
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) if response.status() == 409
+        ));
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_outgoing_queue() {
+        use crate::queue::QueuePolicy;
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let (disconnected_tx, mut disconnected_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut server = Server::new("127.0.0.1:0", disconnected_tx);
+        server.outgoing_queue(2, QueuePolicy::Disconnect);
+        //the handler doesn't yield between the sends, the connection can't write them out in between
+        server
+            .route("@BURST", |params, dispatcher, _state| async move {
+                let n: usize = params.get("n").unwrap().parse().unwrap();
+                for i in 1..=n {
+                    dispatcher.send(format!("@MSG #i {}", i));
+                }
+            })
+            .await;
+        server
+            .route("DISCONNECTED", |params, _dispatcher, state| async move {
+                let _ = state.send(params.get("reason").cloned().unwrap());
+            })
+            .await;
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        client.send(Message::text("@BURST #n 2")).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::text("@MSG #i 1"));
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::text("@MSG #i 2"));
+
+        //more than the queue holds, the client is too slow
+        client.send(Message::text("@BURST #n 3")).await.unwrap();
+        match client.next().await.unwrap().unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(frame.reason, "slow consumer"),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        let reason = tokio::time::timeout(Duration::from_secs(2), disconnected_rx.recv()).await.unwrap();
+        assert_eq!(reason.unwrap(), "slow consumer");
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_close_client() {
+        use crate::routes::CloseCode;
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let (disconnected_tx, mut disconnected_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut server = Server::new("127.0.0.1:0", disconnected_tx);
+        server
+            .route("CONNECTED", |_params, dispatcher, _state| async move {
+                dispatcher.send(format!("@ID #id {}", dispatcher.id()));
+            })
+            .await;
+        server
+            .route("@BYE", |_params, dispatcher, _state| async move {
+                dispatcher.send("@BYE");
+                dispatcher.close("bye");
+            })
+            .await;
+        server
+            .route("@KICK", |params, dispatcher, _state| async move {
+                if let Err(e) = dispatcher.close_client(params.get("id").unwrap(), CloseCode::Policy, "kicked") {
+                    dispatcher.send(format!("@ERR #error {}", e));
+                }
+            })
+            .await;
+        server
+            .route("DISCONNECTED", |params, _dispatcher, state| async move {
+                let _ = state.send(params.get("reason").cloned().unwrap());
+            })
+            .await;
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let url = &format!("ws://{}", addr);
+        let (mut admin, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        admin.next().await.unwrap().unwrap();
+        let (mut alice, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let id = alice.next().await.unwrap().unwrap().into_text().unwrap().replace("@ID #id ", "");
+
+        admin.send(Message::text("@KICK #id nobody")).await.unwrap();
+        assert_eq!(admin.next().await.unwrap().unwrap(), Message::text("@ERR #error unknown connection"));
+
+        admin.send(Message::text(format!("@KICK #id {}", id))).await.unwrap();
+        match alice.next().await.unwrap().unwrap() {
+            Message::Close(Some(frame)) => {
+                assert_eq!(frame.code, CloseCode::Policy);
+                assert_eq!(frame.reason, "kicked");
+            }
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        let reason = tokio::time::timeout(Duration::from_secs(2), disconnected_rx.recv()).await.unwrap();
+        assert_eq!(reason.unwrap(), "kicked");
+
+        //the messages sent before close() are written first
+        admin.send(Message::text("@BYE")).await.unwrap();
+        assert_eq!(admin.next().await.unwrap().unwrap(), Message::text("@BYE"));
+        match admin.next().await.unwrap().unwrap() {
+            Message::Close(Some(frame)) => {
+                assert_eq!(frame.code, CloseCode::Normal);
+                assert_eq!(frame.reason, "bye");
+            }
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        let reason = tokio::time::timeout(Duration::from_secs(2), disconnected_rx.recv()).await.unwrap();
+        assert_eq!(reason.unwrap(), "bye");
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_connection_info() {
+        use crate::parser::Parsed;
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server
+            .route("CONNECTED", |_params, dispatcher, _state| async move {
+                dispatcher.send(format!("@ID #id {}", dispatcher.id()));
+            })
+            .await;
+        server
+            .route("@INFO", |params, dispatcher, _state| async move {
+                let id = params.get("of").unwrap();
+                match dispatcher.connection_info(id) {
+                    Some(info) => dispatcher.send(format!(
+                        "@INFO #in {} #out {} #online {} #count {}",
+                        info.messages_in,
+                        info.messages_out,
+                        dispatcher.is_online(id),
+                        dispatcher.connection_count()
+                    )),
+                    None => dispatcher.send("@OFFLINE"),
+                }
+            })
+            .await;
+        let server = Arc::new(server);
+        let serving = server.clone();
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { serving.serve_listener(listener).await });
+
+        let url = &format!("ws://{}", addr);
+        let next = |message: Message| Parsed::parse(message.into_text().unwrap().to_string());
+        let (mut alice, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let alice_id = next(alice.next().await.unwrap().unwrap()).params["id"].clone();
+        let (mut bob, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let bob_id = next(bob.next().await.unwrap().unwrap()).params["id"].clone();
+
+        alice.send(Message::text(format!("@INFO #of {}", alice_id))).await.unwrap();
+        let info = next(alice.next().await.unwrap().unwrap());
+        assert_eq!(info.params["in"], "1");
+        assert_eq!(info.params["out"], "1");
+        assert_eq!(info.params["online"], "true");
+        assert_eq!(info.params["count"], "2");
+
+        //from outside the handlers
+        assert_eq!(server.connection_count(), 2);
+        assert!(server.connection_ids().iter().any(|id| id.as_str() == bob_id));
+        let info = server.connection_info(&alice_id).unwrap();
+        assert_eq!(info.messages_out, 2);
+        assert_eq!(info.peer.ip().to_string(), "127.0.0.1");
+
+        bob.close(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!server.is_online(&bob_id));
+        alice.send(Message::text(format!("@INFO #of {}", bob_id))).await.unwrap();
+        assert_eq!(alice.next().await.unwrap().unwrap(), Message::text("@OFFLINE"));
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_server_handle() {
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server
+            .route("@JOIN", |_params, dispatcher, _state| async move {
+                dispatcher.join("news");
+                dispatcher.send("@JOINED");
+            })
+            .await;
+        //taken before serve(), used from the test like from a background job
+        let handle = server.handle();
+        let (listener, addr) = listen().await;
+        let serving = tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        client.send(Message::text("@JOIN")).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::text("@JOINED"));
+        assert_eq!(handle.connection_count(), 1);
+        let id = handle.connection_ids().remove(0);
+        assert_eq!(handle.room_members("news"), vec![id.clone()]);
+
+        handle.send_to("@DIRECT", &id);
+        handle.broadcast_to_room("news", "@NEWS");
+        handle.broadcast("@ALL");
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::text("@DIRECT"));
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::text("@NEWS"));
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::text("@ALL"));
+        assert!(handle.try_send_to("@DIRECT", "nobody").is_err());
+
+        handle.shutdown();
+        assert!(matches!(client.next().await.unwrap().unwrap(), Message::Close(Some(_))));
+        let result = tokio::time::timeout(Duration::from_secs(2), serving).await.unwrap();
+        assert!(result.unwrap().is_ok());
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_backplane() {
+        use crate::backplane::{BackplaneMessage, MemoryBackplane, Target};
+        use crate::routes::ConnectionId;
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let msg = BackplaneMessage {
+            origin: "node".to_string(),
+            target: Target::Broadcast {
+                except: Some(ConnectionId::from("a\nb")),
+            },
+            msg: "@CHAT #text 1\n2".to_string(),
+        };
+        assert_eq!(BackplaneMessage::decode(&msg.encode()), Some(msg));
+        let msg = BackplaneMessage {
+            origin: "node".to_string(),
+            target: Target::Rooms(vec!["lobby".to_string(), "a\n1".to_string(), String::new()]),
+            msg: "@PRESENCE".to_string(),
+        };
+        assert_eq!(BackplaneMessage::decode(&msg.encode()), Some(msg));
+
+        //two instances on one backplane
+        let backplane = MemoryBackplane::new();
+        let mut handles = Vec::new();
+        let mut addrs = Vec::new();
+        for _ in 0..2 {
+            let mut server = Server::new("127.0.0.1:0", ());
+            server.backplane(backplane.clone());
+            server
+                .route("CONNECTED", |_params, dispatcher, _state| async move {
+                    dispatcher.join("lobby");
+                    dispatcher.send(format!("@ID #id {}", dispatcher.id()));
+                })
+                .await;
+            server
+                .route("@TELL", |params, dispatcher, _state| async move {
+                    dispatcher.send_to("@TOLD", params.get("to").unwrap());
+                })
+                .await;
+            server
+                .route("@LOBBY", |_params, dispatcher, _state| async move {
+                    dispatcher.broadcast_to_room("lobby", "@IN-LOBBY");
+                })
+                .await;
+            handles.push(server.handle());
+            let (listener, addr) = listen().await;
+            addrs.push(addr);
+            tokio::spawn(async move { server.serve_listener(listener).await });
+        }
+
+        let (mut alice, _) = tokio_tungstenite::connect_async(format!("ws://{}", addrs[0])).await.unwrap();
+        alice.next().await.unwrap().unwrap();
+        let (mut bob, _) = tokio_tungstenite::connect_async(format!("ws://{}", addrs[1])).await.unwrap();
+        let bob_id = bob.next().await.unwrap().unwrap().into_text().unwrap().replace("@ID #id ", "");
+        assert_eq!(handles[0].connection_count(), 1);
+
+        alice.send(Message::text(format!("@TELL #to {}", bob_id))).await.unwrap();
+        assert_eq!(bob.next().await.unwrap().unwrap(), Message::text("@TOLD"));
+
+        //every client gets room sends and broadcasts once
+        alice.send(Message::text("@LOBBY")).await.unwrap();
+        assert_eq!(alice.next().await.unwrap().unwrap(), Message::text("@IN-LOBBY"));
+        assert_eq!(bob.next().await.unwrap().unwrap(), Message::text("@IN-LOBBY"));
+        handles[1].broadcast("@ALL");
+        assert_eq!(alice.next().await.unwrap().unwrap(), Message::text("@ALL"));
+        assert_eq!(bob.next().await.unwrap().unwrap(), Message::text("@ALL"));
+        handles[0].broadcast("@END");
+        assert_eq!(alice.next().await.unwrap().unwrap(), Message::text("@END"));
+        assert_eq!(bob.next().await.unwrap().unwrap(), Message::text("@END"));
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_backplane_errors() {
+        use crate::backplane::{Backplane, BackplaneError, BackplaneMessage, MemoryBackplane, Target};
+        use crate::error::ServerError;
+        use crate::server::Server;
+        use futures_util::StreamExt;
+        use futures_util::stream::BoxStream;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Message;
+
+        //the first subscription fails and ends, the next one works
+        struct Flaky {
+            inner: MemoryBackplane,
+            failed: AtomicBool,
+        }
+        impl Backplane for Flaky {
+            fn publish(&self, msg: BackplaneMessage) {
+                self.inner.publish(msg);
+            }
+            fn subscribe(&self) -> BoxStream<'static, Result<BackplaneMessage, BackplaneError>> {
+                match self.failed.swap(true, Ordering::SeqCst) {
+                    false => Box::pin(futures_util::stream::iter([Err("broker down".into())])),
+                    true => self.inner.subscribe(),
+                }
+            }
+        }
+
+        let backplane = MemoryBackplane::new();
+        let (error_tx, mut error_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut server = Server::new("127.0.0.1:0", ());
+        server.backplane(Flaky {
+            inner: backplane.clone(),
+            failed: AtomicBool::new(false),
+        });
+        server.on_error(move |error, _peer, _state| {
+            let error_tx = error_tx.clone();
+            async move {
+                let _ = error_tx.send(error);
+            }
+        });
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        //the error is reported, and the server subscribes again when the stream ends
+        let error = error_rx.recv().await.unwrap();
+        assert!(matches!(error, ServerError::Backplane(e) if e.to_string() == "broker down"));
+        assert!(matches!(error_rx.recv().await.unwrap(), ServerError::Backplane(_)));
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        backplane.publish(BackplaneMessage {
+            origin: "other".to_string(),
+            target: Target::Broadcast { except: None },
+            msg: "@ALL".to_string(),
+        });
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::text("@ALL"));
+        assert!(error_rx.try_recv().is_err());
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_backplane_backoff() {
+        use crate::backplane::{Backplane, BackplaneError, BackplaneMessage, Target};
+        use crate::hub::Hub;
+        use futures_util::stream::BoxStream;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+        use tokio::time::Instant;
+
+        //every subscription ends right away, the fourth one after a message
+        struct Ending {
+            subscribed: Mutex<Vec<Instant>>,
+        }
+        impl Backplane for Ending {
+            fn publish(&self, _msg: BackplaneMessage) {}
+            fn subscribe(&self) -> BoxStream<'static, Result<BackplaneMessage, BackplaneError>> {
+                let mut subscribed = self.subscribed.lock().unwrap();
+                subscribed.push(Instant::now());
+                let msg = BackplaneMessage {
+                    origin: "other".to_string(),
+                    target: Target::Broadcast { except: None },
+                    msg: "@ALL".to_string(),
+                };
+                match subscribed.len() {
+                    4 => Box::pin(futures_util::stream::iter([Ok(msg)])),
+                    _ => Box::pin(futures_util::stream::empty()),
+                }
+            }
+        }
+
+        let backplane = Arc::new(Ending {
+            subscribed: Mutex::new(Vec::new()),
+        });
+        let hub = Hub::default();
+        *hub.backplane.write().unwrap() = Some(backplane.clone());
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let (ended_tx, mut ended_rx) = tokio::sync::mpsc::unbounded_channel();
+        let subscribing = hub.subscribe(
+            async move {
+                let _ = stop_rx.await;
+            },
+            move |error| {
+                let ended_tx = ended_tx.clone();
+                async move {
+                    let _ = ended_tx.send(error.to_string());
+                }
+            },
+        );
+        let stopping = async move {
+            for _ in 0..5 {
+                assert_eq!(ended_rx.recv().await.unwrap(), "the backplane subscription ended");
+            }
+            stop_tx.send(()).unwrap();
+        };
+        tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(subscribing, stopping) })
+            .await
+            .unwrap();
+
+        //the wait doubles while the subscriptions end, and starts over after a message
+        let subscribed = backplane.subscribed.lock().unwrap();
+        let waits: Vec<Duration> = subscribed.windows(2).map(|w| w[1] - w[0]).collect();
+        assert!(waits[0] >= Duration::from_millis(100));
+        assert!(waits[1] >= Duration::from_millis(200));
+        assert!(waits[2] >= Duration::from_millis(400));
+        assert!(waits[3] >= Duration::from_millis(100) && waits[3] < Duration::from_millis(400));
+    }
+
+    //needs a redis server on 127.0.0.1:6379: cargo test --features redis -- --ignored test_redis_backplane
+    #[cfg(feature = "redis")]
+    #[tokio::test]
+    #[ignore]
+    async fn test_redis_backplane() {
+        use crate::backplane::RedisBackplane;
+        use crate::error::ServerError;
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let url = "redis://127.0.0.1/";
+        let channel = format!("wroustr-test-{}", uuid::Uuid::new_v4().simple());
+        let (error_tx, mut error_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut addrs = Vec::new();
+        for _ in 0..2 {
+            let mut server = Server::new("127.0.0.1:0", ());
+            server.backplane(RedisBackplane::connect(url, channel.clone()).await.unwrap());
+            server
+                .route("CONNECTED", |_params, dispatcher, _state| async move {
+                    dispatcher.join("lobby");
+                    dispatcher.send(format!("@ID #id {}", dispatcher.id()));
+                })
+                .await;
+            server
+                .route("@TELL", |params, dispatcher, _state| async move {
+                    dispatcher.send_to("@TOLD", params.get("to").unwrap());
+                })
+                .await;
+            server
+                .route("@LOBBY", |_params, dispatcher, _state| async move {
+                    dispatcher.broadcast_to_room("lobby", "@IN-LOBBY");
+                })
+                .await;
+            let error_tx = error_tx.clone();
+            server.on_error(move |error, _peer, _state| {
+                let error_tx = error_tx.clone();
+                async move {
+                    let _ = error_tx.send(error);
+                }
+            });
+            let (listener, addr) = listen().await;
+            addrs.push(addr);
+            tokio::spawn(async move { server.serve_listener(listener).await });
+        }
+
+        //the servers subscribe in the background, waits until redis has both subscriptions
+        let mut redis = redis::Client::open(url).unwrap().get_multiplexed_async_connection().await.unwrap();
+        let subscribed = async |redis: &mut redis::aio::MultiplexedConnection| {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    let (_, count): (String, usize) =
+                        redis::cmd("PUBSUB").arg("NUMSUB").arg(&channel).query_async(redis).await.unwrap();
+                    if count == 2 {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+        };
+        subscribed(&mut redis).await;
+
+        let (mut alice, _) = tokio_tungstenite::connect_async(format!("ws://{}", addrs[0])).await.unwrap();
+        alice.next().await.unwrap().unwrap();
+        let (mut bob, _) = tokio_tungstenite::connect_async(format!("ws://{}", addrs[1])).await.unwrap();
+        let bob_id = bob.next().await.unwrap().unwrap().into_text().unwrap().replace("@ID #id ", "");
+
+        alice.send(Message::text(format!("@TELL #to {}", bob_id))).await.unwrap();
+        assert_eq!(bob.next().await.unwrap().unwrap(), Message::text("@TOLD"));
+        alice.send(Message::text("@LOBBY")).await.unwrap();
+        assert_eq!(alice.next().await.unwrap().unwrap(), Message::text("@IN-LOBBY"));
+        assert_eq!(bob.next().await.unwrap().unwrap(), Message::text("@IN-LOBBY"));
+
+        //redis drops the subscriptions, both servers report it and subscribe again
+        let _: usize = redis::cmd("CLIENT").arg("KILL").arg("TYPE").arg("pubsub").query_async(&mut redis).await.unwrap();
+        for _ in 0..2 {
+            let error = tokio::time::timeout(Duration::from_secs(5), error_rx.recv()).await.unwrap();
+            assert!(matches!(error, Some(ServerError::Backplane(e)) if e.to_string() == "lost the connection to redis"));
+        }
+        subscribed(&mut redis).await;
+        alice.send(Message::text(format!("@TELL #to {}", bob_id))).await.unwrap();
+        assert_eq!(bob.next().await.unwrap().unwrap(), Message::text("@TOLD"));
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_presence() {
+        use crate::parser::Parsed;
+        use crate::presence::Presence;
+        use crate::server::Server;
+        use futures_util::{SinkExt, StreamExt};
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server.presence(Presence::new().watchers("watchers").room_events());
+        server
+            .route("CONNECTED", |_params, dispatcher, _state| async move {
+                dispatcher.send(format!("@ID #id {}", dispatcher.id()));
+            })
+            .await;
+        server
+            .route("@JOIN", |params, dispatcher, _state| async move {
+                dispatcher.join(params.get("room").unwrap());
+            })
+            .await;
+        server
+            .route("@STATUS", |params, dispatcher, _state| async move {
+                dispatcher.set_status(params.get("status").unwrap());
+                let status = dispatcher.status_of(dispatcher.id()).unwrap();
+                dispatcher.send(format!("@STATUS-OF #status \"{}\"", status));
+            })
+            .await;
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let url = &format!("ws://{}", addr);
+        let next = |message: Message| Parsed::parse(message.into_text().unwrap().to_string());
+        let event = |message: Message| {
+            let parsed = next(message);
+            assert_eq!(parsed.command, "@PRESENCE");
+            let room = parsed.params.get("room").cloned().unwrap_or_default();
+            (parsed.params["event"].clone(), parsed.params["id"].clone(), parsed.params["status"].clone(), room)
+        };
+
+        let (mut watcher, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let watcher_id = next(watcher.next().await.unwrap().unwrap()).params["id"].clone();
+        watcher.send(Message::text("@JOIN #room watchers")).await.unwrap();
+        assert_eq!(
+            event(watcher.next().await.unwrap().unwrap()),
+            ("joined".into(), watcher_id.clone(), "online".into(), "watchers".into())
+        );
+
+        let (mut alice, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let alice_id = next(alice.next().await.unwrap().unwrap()).params["id"].clone();
+        assert_eq!(
+            event(watcher.next().await.unwrap().unwrap()),
+            ("connected".into(), alice_id.clone(), "online".into(), "".into())
+        );
+        let (mut bob, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let bob_id = next(bob.next().await.unwrap().unwrap()).params["id"].clone();
+        assert_eq!(event(watcher.next().await.unwrap().unwrap()).1, bob_id);
+        bob.send(Message::text("@JOIN #room lobby")).await.unwrap();
+        assert_eq!(event(bob.next().await.unwrap().unwrap()).1, bob_id);
+        //the watcher is in the lobby too
+        watcher.send(Message::text("@JOIN #room lobby")).await.unwrap();
+        assert_eq!(event(watcher.next().await.unwrap().unwrap()).3, "lobby");
+        assert_eq!(event(bob.next().await.unwrap().unwrap()).1, watcher_id);
+
+        //the members of the room see who joins and who changes the status
+        alice.send(Message::text("@JOIN #room lobby")).await.unwrap();
+        let joined = ("joined".into(), alice_id.clone(), "online".into(), "lobby".into());
+        assert_eq!(event(bob.next().await.unwrap().unwrap()), joined);
+        assert_eq!(event(alice.next().await.unwrap().unwrap()), joined);
+        assert_eq!(event(watcher.next().await.unwrap().unwrap()), joined);
+        alice.send(Message::text("@STATUS #status \"be right back\"")).await.unwrap();
+        let status = ("status".into(), alice_id.clone(), "be right back".into(), "".into());
+        assert_eq!(event(watcher.next().await.unwrap().unwrap()), status);
+        assert_eq!(event(bob.next().await.unwrap().unwrap()), status);
+        assert_eq!(event(alice.next().await.unwrap().unwrap()), status);
+        assert_eq!(next(alice.next().await.unwrap().unwrap()).params["status"], "be right back");
+
+        alice.close(None).await.unwrap();
+        let disconnected = ("disconnected".into(), alice_id, "offline".into(), "".into());
+        assert_eq!(event(watcher.next().await.unwrap().unwrap()), disconnected);
+        assert_eq!(event(bob.next().await.unwrap().unwrap()), disconnected);
+        //the watcher got every event once, although it's in the watchers room and the lobby
+        assert!(tokio::time::timeout(Duration::from_millis(200), watcher.next()).await.is_err());
+    }
+
+    #[cfg(all(feature = "server", feature = "client", feature = "deflate"))]
+    #[tokio::test]
+    async fn test_permessage_deflate() {
+        use crate::client::Connector;
+        use crate::routes::WebSocketConfig;
+        use crate::server::Server;
+        use std::time::Duration;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server.permessage_deflate();
+        server.websocket_config(WebSocketConfig::default().max_message_size(Some(64 * 1024)));
+        server
+            .route("CONNECTED", |_params, dispatcher, _state| async move {
+                dispatcher.send(format!("@BIG #text {}", "a".repeat(10000)));
+            })
+            .await;
+        server
+            .route("@ECHO", |params, dispatcher, _state| async move {
+                dispatcher.send(format!("@LEN #len {}", params.get("text").unwrap().len()));
+            })
+            .await;
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        //the server accepts the offer and compresses the message (fin, rsv1, text and a short length)
+        let mut raw = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        raw.write_all(
+            b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(raw.read_u8().await.unwrap());
+        }
+        assert!(String::from_utf8(head).unwrap().contains("permessage-deflate"));
+        assert_eq!(raw.read_u8().await.unwrap(), 0xc1);
+        assert!(raw.read_u8().await.unwrap() < 126);
+
+        //the connector offers it too, the big message goes both ways compressed
+        let (len_tx, mut len_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut connector = Connector::new(format!("ws://{}", addr), len_tx);
+        connector.permessage_deflate();
+        connector.route("@BIG", |params, dispatcher, _state| async move {
+            dispatcher.send(format!("@ECHO #text {}", params.get("text").unwrap()));
+        });
+        connector.route("@LEN", |params, _dispatcher, state| async move {
+            let _ = state.send(params.get("len").cloned().unwrap());
+        });
+        let _dispatcher = connector.connect().await;
+        let len = tokio::time::timeout(Duration::from_secs(2), len_rx.recv()).await.unwrap();
+        assert_eq!(len.unwrap(), "10000");
+    }
+
+    #[cfg(all(feature = "server", feature = "deflate"))]
+    #[tokio::test]
+    async fn test_deflate_frame_limit() {
+        use crate::routes::WebSocketConfig;
+        use crate::server::Server;
+        use std::time::Duration;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut server = Server::new("127.0.0.1:0", ());
+        server.permessage_deflate();
+        server.websocket_config(WebSocketConfig::default().max_frame_size(Some(1024)));
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+
+        let mut raw = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        raw.write_all(
+            b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Extensions: permessage-deflate\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(raw.read_u8().await.unwrap());
+        }
+        assert!(String::from_utf8(head).unwrap().contains("permessage-deflate"));
+
+        //a compressed text frame that claims 1 TiB, followed by a part of the payload
+        let mut frame = vec![0xc1, 0x80 | 127];
+        frame.extend_from_slice(&(1u64 << 40).to_be_bytes());
+        frame.extend_from_slice(&[1, 2, 3, 4]);
+        frame.extend_from_slice(&[0; 4096]);
+        let _ = raw.write_all(&frame).await;
+
+        //the server drops the connection instead of buffering the payload
+        let mut buf = [0u8; 64];
+        let dropped = async {
+            loop {
+                match raw.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => continue,
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(2), dropped).await.unwrap();
+    }
+
+    #[cfg(all(feature = "server", feature = "client"))]
+    #[tokio::test]
+    async fn test_disconnect_reason() {
+        use crate::client::Connector;
+        use crate::disconnect::{Disconnect, DisconnectKind};
+        use crate::routes::CloseCode;
+        use crate::server::Server;
+        use futures_util::SinkExt;
+        use std::time::Duration;
+        use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let (disconnected_tx, mut disconnected_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut server = Server::new("127.0.0.1:0", disconnected_tx);
+        server
+            .route("DISCONNECTED", |params, _dispatcher, state| async move {
+                let _ = state.send(Disconnect::from_params(&params).unwrap());
+            })
+            .await;
+        server
+            .route("@KICK", |_params, dispatcher, _state| async move {
+                let _ = dispatcher.close_client(dispatcher.id(), CloseCode::Policy, "kicked");
+            })
+            .await;
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move { server.serve_listener(listener).await });
+        let mut next = async || {
+            tokio::time::timeout(Duration::from_secs(2), disconnected_rx.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        };
+
+        //a clean close with the client's code and reason
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let frame = CloseFrame {
+            code: CloseCode::Normal,
+            reason: "done".into(),
+        };
+        ws.send(Message::Close(Some(frame))).await.unwrap();
+        let disconnect = next().await;
+        assert_eq!(disconnect.kind, DisconnectKind::Closed);
+        assert_eq!(disconnect.code, Some(CloseCode::Normal));
+        assert_eq!(disconnect.reason, "done");
+        assert!(disconnect.duration >= Duration::from_millis(100));
+
+        //the connection drops without a close frame
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        drop(ws);
+        let disconnect = next().await;
+        assert_eq!(disconnect.kind, DisconnectKind::Error);
+        assert_eq!(disconnect.code, None);
+        assert!(disconnect.error.is_some());
+
+        //kicked by the server, the connector sees the close frame
+        let (client_tx, mut client_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut connector = Connector::new(format!("ws://{}", addr), client_tx);
+        connector.route("CONNECTED", |_params, dispatcher, _state| async move {
+            dispatcher.send("@KICK");
+        });
+        connector.route("DISCONNECTED", |params, _dispatcher, state| async move {
+            let _ = state.send(Disconnect::from_params(&params).unwrap());
+        });
+        let _dispatcher = connector.connect().await;
+        let disconnect = next().await;
+        assert_eq!(disconnect.kind, DisconnectKind::Kicked);
+        assert_eq!(disconnect.code, Some(CloseCode::Policy));
+        assert_eq!(disconnect.reason, "kicked");
+        let disconnect = tokio::time::timeout(Duration::from_secs(2), client_rx.recv()).await.unwrap().unwrap();
+        assert_eq!(disconnect.kind, DisconnectKind::Closed);
+        assert_eq!(disconnect.code, Some(CloseCode::Policy));
+        assert_eq!(disconnect.reason, "kicked");
+    }
+}
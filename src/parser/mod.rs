@@ -16,7 +16,7 @@ impl Parsed {
         let peaces = Self::tokenize(copy.as_str());
         #[cfg(feature = "debug")]
         println!("PARSE: {:?}", peaces);
-        if peaces.len() < 1 {
+        if peaces.is_empty() {
             return Self {
                 params: Params::new(),
                 command: "".to_string()
@@ -36,9 +36,9 @@ impl Parsed {
             let key = peaces[i].clone();
             let value = peaces[i + 1].clone();
 
-            if key.starts_with("#") {
+            if let Some(key) = key.strip_prefix("#") {
                 params.insert(
-                    key[1..].to_string(),
+                    key.to_string(),
                     value
                         .trim()
                         .trim_matches('"')
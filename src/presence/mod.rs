@@ -0,0 +1,86 @@
+use crate::routes::ConnectionId;
+
+//presence tracking, set with Server::presence()
+//every connection has a status ("online" by default, changed with ServerDispatcher::set_status()),
+//and the interested clients get @PRESENCE events when someone connects, disconnects, changes status,
+//joins or leaves a room
+#[derive(Debug, Clone)]
+pub struct Presence {
+    pub(crate) command: String,
+    pub(crate) watchers: Option<String>,
+    pub(crate) room_events: bool,
+}
+
+impl Presence {
+    //by default no one gets events, see watchers() and room_events()
+    pub fn new() -> Self {
+        Self {
+            command: "@PRESENCE".to_string(),
+            watchers: None,
+            room_events: false,
+        }
+    }
+
+    //the members of this room get the connected, disconnected and status events of every client
+    pub fn watchers(mut self, room: impl Into<String>) -> Self {
+        self.watchers = Some(room.into());
+        self
+    }
+
+    //the members of every room get the joined, left, disconnected and status events of the room's members
+    pub fn room_events(mut self) -> Self {
+        self.room_events = true;
+        self
+    }
+
+    //the command of the events (default: @PRESENCE)
+    pub fn command(mut self, command: impl Into<String>) -> Self {
+        self.command = command.into();
+        self
+    }
+
+    //e.g. @PRESENCE #event joined #id abc #status online #room lobby
+    pub(crate) fn message(&self, event: &PresenceEvent, id: &ConnectionId, status: &str) -> String {
+        let mut msg = format!("{} #event {} #id {} #status {}", self.command, event.name(), id, quoted(status));
+        if let PresenceEvent::Joined(room) | PresenceEvent::Left(room) = event {
+            msg.push_str(&format!(" #room {}", quoted(room)));
+        }
+        msg
+    }
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//what happened to a connection
+pub(crate) enum PresenceEvent {
+    Connected,
+    Disconnected,
+    Status,
+    Joined(String),
+    Left(String),
+}
+
+impl PresenceEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            PresenceEvent::Connected => "connected",
+            PresenceEvent::Disconnected => "disconnected",
+            PresenceEvent::Status => "status",
+            PresenceEvent::Joined(_) => "joined",
+            PresenceEvent::Left(_) => "left",
+        }
+    }
+}
+
+//values with whitespace are quoted, so the parser keeps them in one parameter
+fn quoted(value: &str) -> String {
+    if value.contains(char::is_whitespace) {
+        format!("\"{}\"", value)
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,179 @@
+use crate::disconnect::DisconnectKind;
+use crate::error::SendError;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Utf8Bytes;
+
+//what happens when a message is sent to a full outgoing queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueuePolicy {
+    //send_async() waits until there's space, the other sends (they can't wait) drop the message
+    Wait,
+    //the oldest queued message is dropped to make space
+    DropOldest,
+    //the new message is dropped
+    #[default]
+    DropNewest,
+    //the client can't keep up, its connection is closed (#reason 'slow consumer')
+    Disconnect,
+}
+
+//what the connection task writes next
+pub(crate) enum Outgoing {
+    Message(Utf8Bytes),
+    //closes the connection (close(), close_client() or a slow consumer)
+    Close(CloseFrame, DisconnectKind),
+}
+
+//the outgoing messages of a connection, written to the websocket by the connection task
+pub(crate) struct Outbox {
+    state: Mutex<Queue>,
+    //None means unbounded
+    capacity: Option<usize>,
+    policy: QueuePolicy,
+    //wakes up the connection task
+    readable: Notify,
+    //wakes up the senders waiting for space
+    writable: Notify,
+    //wakes up the suspended connection that's waiting for its client (session resumption)
+    closing: Notify,
+}
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<Utf8Bytes>,
+    //the connection has to be closed with this frame once the queued messages are written
+    close_frame: Option<(CloseFrame, DisconnectKind)>,
+    closed: bool,
+}
+
+impl Outbox {
+    pub(crate) fn new(capacity: Option<usize>, policy: QueuePolicy) -> Self {
+        Self {
+            state: Mutex::new(Queue::default()),
+            capacity,
+            policy,
+            readable: Notify::new(),
+            writable: Notify::new(),
+            closing: Notify::new(),
+        }
+    }
+
+    //queues the message without waiting, the policy decides what happens if the queue is full
+    pub(crate) fn push(&self, msg: Utf8Bytes) -> Result<(), SendError> {
+        let mut queue = self.state.lock().unwrap();
+        if queue.closed || queue.close_frame.is_some() {
+            return Err(SendError::Closed);
+        }
+        if self.capacity.is_some_and(|capacity| queue.messages.len() >= capacity) {
+            match self.policy {
+                QueuePolicy::Wait | QueuePolicy::DropNewest => return Err(SendError::QueueFull),
+                QueuePolicy::DropOldest => {
+                    queue.messages.pop_front();
+                }
+                //the queued messages are dropped, the client is closed right away
+                QueuePolicy::Disconnect => {
+                    queue.messages.clear();
+                    drop(queue);
+                    let frame = CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: Utf8Bytes::from_static("slow consumer"),
+                    };
+                    self.request_close(frame, DisconnectKind::SlowConsumer);
+                    return Err(SendError::QueueFull);
+                }
+            }
+        }
+        queue.messages.push_back(msg);
+        drop(queue);
+        self.readable.notify_one();
+        Ok(())
+    }
+
+    //same as push(), but with the Wait policy it waits for space instead of dropping the message
+    pub(crate) async fn push_wait(&self, msg: Utf8Bytes) -> Result<(), SendError> {
+        if self.policy != QueuePolicy::Wait {
+            return self.push(msg);
+        }
+        loop {
+            let writable = self.writable.notified();
+            {
+                let mut queue = self.state.lock().unwrap();
+                if queue.closed || queue.close_frame.is_some() {
+                    return Err(SendError::Closed);
+                }
+                if self.capacity.is_none_or(|capacity| queue.messages.len() < capacity) {
+                    queue.messages.push_back(msg);
+                    drop(queue);
+                    self.readable.notify_one();
+                    return Ok(());
+                }
+            }
+            writable.await;
+        }
+    }
+
+    //the next message to write, or the close frame once every queued message was written
+    pub(crate) async fn recv(&self) -> Outgoing {
+        loop {
+            {
+                let mut queue = self.state.lock().unwrap();
+                if let Some(msg) = queue.messages.pop_front() {
+                    drop(queue);
+                    self.writable.notify_one();
+                    return Outgoing::Message(msg);
+                }
+                if let Some((frame, kind)) = &queue.close_frame {
+                    return Outgoing::Close(frame.clone(), *kind);
+                }
+            }
+            self.readable.notified().await;
+        }
+    }
+
+    //the next message, if there's one queued
+    pub(crate) fn try_recv(&self) -> Option<Utf8Bytes> {
+        let msg = self.state.lock().unwrap().messages.pop_front();
+        if msg.is_some() {
+            self.writable.notify_one();
+        }
+        msg
+    }
+
+    //asks the connection task to close the connection, later messages are refused
+    //the first request wins, the messages queued before it are still written
+    pub(crate) fn request_close(&self, frame: CloseFrame, kind: DisconnectKind) {
+        let mut queue = self.state.lock().unwrap();
+        if queue.closed || queue.close_frame.is_some() {
+            return;
+        }
+        queue.close_frame = Some((frame, kind));
+        drop(queue);
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+        self.closing.notify_waiters();
+    }
+
+    //completes when the connection has to be closed, with the close frame and the kind of the close
+    pub(crate) async fn close_requested(&self) -> (CloseFrame, DisconnectKind) {
+        loop {
+            let closing = self.closing.notified();
+            if let Some(close) = &self.state.lock().unwrap().close_frame {
+                return close.clone();
+            }
+            closing.await;
+        }
+    }
+
+    //the connection is gone: the queued messages are dropped and nothing is accepted anymore
+    pub(crate) fn close(&self) {
+        let mut queue = self.state.lock().unwrap();
+        queue.closed = true;
+        queue.messages.clear();
+        drop(queue);
+        self.writable.notify_waiters();
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+//what happens to the messages over the limit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitPolicy {
+    //the message is skipped silently
+    Drop,
+    //the message is skipped and the client gets a @RATE-LIMITED message
+    Reply,
+    //the connection is closed (DISCONNECTED runs with #reason 'rate limited')
+    Disconnect,
+}
+
+//token bucket: `rate` messages every `per`, with bursts up to `burst` messages
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub(crate) rate: u32,
+    pub(crate) per: Duration,
+    pub(crate) burst: u32,
+    pub(crate) policy: RateLimitPolicy,
+}
+
+impl RateLimit {
+    //by default the burst is the same as the rate, and the policy is Drop
+    //panics if `rate` or `per` is zero, the bucket would never refill (or refill infinitely)
+    pub fn new(rate: u32, per: Duration) -> Self {
+        assert!(rate > 0, "rate limit rate must be greater than zero");
+        assert!(!per.is_zero(), "rate limit period must be greater than zero");
+        Self {
+            rate,
+            per,
+            burst: rate,
+            policy: RateLimitPolicy::Drop,
+        }
+    }
+
+    //panics if `burst` is zero, no message would ever get through
+    pub fn burst(mut self, burst: u32) -> Self {
+        assert!(burst > 0, "rate limit burst must be greater than zero");
+        self.burst = burst;
+        self
+    }
+
+    pub fn policy(mut self, policy: RateLimitPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+//the bucket of one connection (or one route of a connection)
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    pub(crate) fn policy(&self) -> RateLimitPolicy {
+        self.limit.policy
+    }
+
+    //takes a token for a message, false if the bucket is empty
+    pub(crate) fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() / self.limit.per.as_secs_f64()
+            * self.limit.rate as f64;
+        self.tokens = (self.tokens + refill).min(self.limit.burst as f64);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio_tungstenite::tungstenite::http::Uri;
+
+pub use tokio_tungstenite::tungstenite::http::HeaderMap;
+
+//the http upgrade request of a connection, available with ServerDispatcher::request()
+#[derive(Debug, Clone)]
+pub struct UpgradeRequest {
+    //e.g. /chat
+    pub path: String,
+    //the decoded query parameters (?token=abc)
+    pub query: HashMap<String, String>,
+    pub headers: HeaderMap,
+    //the cookies of the Cookie headers
+    pub cookies: HashMap<String, String>,
+    pub peer: SocketAddr,
+}
+
+impl UpgradeRequest {
+    pub(crate) fn new(uri: &Uri, headers: &HeaderMap, peer: SocketAddr) -> Self {
+        let query = uri
+            .query()
+            .map(|query| {
+                query
+                    .split('&')
+                    .filter(|pair| !pair.is_empty())
+                    .map(|pair| {
+                        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                        (decode(key), decode(value))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let cookies = headers
+            .get_all("cookie")
+            .iter()
+            .filter_map(|header| header.to_str().ok())
+            .flat_map(|header| header.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .map(|(name, value)| (name.to_string(), value.trim_matches('"').to_string()))
+            .collect();
+        Self {
+            path: uri.path().to_string(),
+            query,
+            headers: headers.clone(),
+            cookies,
+            peer,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(String::as_str)
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies.get(name).map(String::as_str)
+    }
+}
+
+//percent-decoding for the query string ('+' is a space)
+fn decode(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = |byte: u8| (byte as char).to_digit(16);
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        decoded.push((high * 16 + low) as u8);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,91 @@
+#[cfg(feature = "server")]
+use crate::admission::{ReservedId, Slot};
+#[cfg(feature = "server")]
+use crate::routes::ConnectionId;
+#[cfg(feature = "server")]
+use crate::queue::Outbox;
+#[cfg(feature = "server")]
+use crate::session::Session;
+#[cfg(feature = "server")]
+use std::collections::HashMap;
+#[cfg(feature = "server")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "server")]
+use tokio::sync::oneshot;
+#[cfg(feature = "server")]
+use tokio::time::Instant;
+#[cfg(feature = "server")]
+use uuid::Uuid;
+
+//the header the Connector presents its resume token in
+pub const RESUME_HEADER: &str = "x-wroustr-resume";
+
+//the message the server sends the resume token in: @RESUME-TOKEN #token ...
+pub const RESUME_COMMAND: &str = "@RESUME-TOKEN";
+
+//the queue and the session of a connection, handed over to the connection that resumes it
+#[cfg(feature = "server")]
+pub(crate) struct Resumed {
+    //the messages sent while the client was away are waiting here
+    pub(crate) outbox: Arc<Outbox>,
+    pub(crate) session: Session,
+    //when the client connected first, DISCONNECTED reports the duration of the whole session
+    pub(crate) connected_at: Instant,
+}
+
+//a disconnected connection, waiting to be resumed with its token
+#[cfg(feature = "server")]
+pub(crate) struct Suspended {
+    pub(crate) id: ReservedId,
+    //the connection keeps counting against the limits while suspended
+    pub(crate) slot: Slot,
+    //the upgrade path, the connection is only resumed on the same endpoint
+    pub(crate) path: String,
+    pub(crate) resumed: Resumed,
+    //the connection that resumes it sends on it once it's upgraded, wakes up the task of the disconnected connection
+    pub(crate) took_over: oneshot::Sender<()>,
+}
+
+//the suspended connections by resume token
+#[cfg(feature = "server")]
+#[derive(Default)]
+pub(crate) struct Resumption {
+    suspended: Mutex<HashMap<String, Suspended>>,
+}
+
+#[cfg(feature = "server")]
+impl Resumption {
+    //a new random token, every connection gets a new one (also after resuming)
+    pub(crate) fn token() -> String {
+        Uuid::new_v4().simple().to_string()
+    }
+
+    pub(crate) fn suspend(&self, token: String, suspended: Suspended) {
+        self.suspended.lock().unwrap().insert(token, suspended);
+    }
+
+    //takes the suspended connection, None if the token is unknown, expired or already used
+    pub(crate) fn take(&self, token: &str) -> Option<Suspended> {
+        self.suspended.lock().unwrap().remove(token)
+    }
+
+    //same as take(), but leaves the connection suspended if accept returns false
+    pub(crate) fn take_if(&self, token: &str, accept: impl FnOnce(&Suspended) -> bool) -> Option<Suspended> {
+        let mut suspended = self.suspended.lock().unwrap();
+        if !accept(suspended.get(token)?) {
+            return None;
+        }
+        suspended.remove(token)
+    }
+
+    //takes the suspended connection with the id, for a client that comes back with its id but without the token
+    pub(crate) fn take_id(&self, id: &ConnectionId, accept: impl FnOnce(&Suspended) -> bool) -> Option<Suspended> {
+        let mut suspended = self.suspended.lock().unwrap();
+        let (token, found) = suspended.iter().find(|(_, s)| s.id.id == *id)?;
+        if !accept(found) {
+            return None;
+        }
+        let token = token.clone();
+        suspended.remove(&token)
+    }
+}
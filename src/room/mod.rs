@@ -0,0 +1,56 @@
+use std::collections::{HashMap, HashSet};
+use crate::routes::ConnectionId;
+
+//room name -> connections in the room
+//empty rooms are removed, so a room exists as long as someone is in it
+#[derive(Default)]
+pub(crate) struct Rooms {
+    members: HashMap<String, HashSet<ConnectionId>>,
+}
+
+impl Rooms {
+    //false if the connection was already in the room
+    pub(crate) fn join(&mut self, room: String, id: ConnectionId) -> bool {
+        self.members.entry(room).or_default().insert(id)
+    }
+
+    //false if the connection wasn't in the room
+    pub(crate) fn leave(&mut self, room: &str, id: &ConnectionId) -> bool {
+        let Some(members) = self.members.get_mut(room) else {
+            return false;
+        };
+        let left = members.remove(id);
+        if members.is_empty() {
+            self.members.remove(room);
+        }
+        left
+    }
+
+    //removes the connection from every room (when it disconnects)
+    pub(crate) fn leave_all(&mut self, id: &ConnectionId) {
+        self.members.retain(|_, members| {
+            members.remove(id);
+            !members.is_empty()
+        });
+    }
+
+    pub(crate) fn members(&self, room: &str) -> Vec<ConnectionId> {
+        self.members
+            .get(room)
+            .map(|members| members.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn contains(&self, room: &str, id: &ConnectionId) -> bool {
+        self.members.get(room).is_some_and(|members| members.contains(id))
+    }
+
+    //the rooms the connection is in
+    pub(crate) fn rooms_of(&self, id: &ConnectionId) -> Vec<String> {
+        self.members
+            .iter()
+            .filter(|(_, members)| members.contains(id))
+            .map(|(room, _)| room.clone())
+            .collect()
+    }
+}
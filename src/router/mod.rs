@@ -0,0 +1,55 @@
+use crate::handler::{into_callback, Handler};
+use crate::interceptor::{InterceptorType, ServerInterceptor};
+use crate::layer::ServerLayer;
+use crate::routes::{Params, ServerDispatcher, ServerRoutes, State};
+use std::sync::Arc;
+
+//routes, layers, interceptors and a state for one upgrade path, mounted with Server::mount()
+//the state type doesn't have to be the same as the server's
+pub struct Router<S> {
+    pub(crate) routes: Vec<ServerRoutes<S>>,
+    pub(crate) state: State<S>,
+    pub(crate) layers: Vec<ServerLayer<S>>,
+    pub(crate) incoming_ir: Arc<Option<ServerInterceptor<S>>>,
+    pub(crate) outgoing_ir: Arc<Option<ServerInterceptor<S>>>,
+}
+
+impl<S: Send + Sync + 'static> Router<S> {
+    pub fn new(state: S) -> Self {
+        Self {
+            routes: Vec::new(),
+            state: State::new(state),
+            layers: Vec::new(),
+            incoming_ir: Arc::new(None),
+            outgoing_ir: Arc::new(None),
+        }
+    }
+
+    pub fn route<F, Fut>(&mut self, name: impl Into<String>, callback: F)
+    where
+        F: Fn(Params, ServerDispatcher, State<S>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.route_handler(name, callback);
+    }
+
+    //same as route(), but takes anything that implements Handler
+    pub fn route_handler(&mut self, name: impl Into<String>, handler: impl Handler<S, ServerDispatcher>) {
+        self.routes.push(ServerRoutes {
+            name: name.into(),
+            callback: into_callback(handler),
+        });
+    }
+
+    pub fn layer(&mut self, layer: ServerLayer<S>) {
+        self.layers.push(layer);
+    }
+
+    pub fn intercept(&mut self, interceptor: ServerInterceptor<S>) {
+        if interceptor.r#type == InterceptorType::INCOMING {
+            self.incoming_ir = Arc::new(Some(interceptor));
+        } else {
+            self.outgoing_ir = Arc::new(Some(interceptor));
+        }
+    }
+}
@@ -1,23 +1,67 @@
+#[cfg(any(feature = "server", feature = "client"))]
+use crate::error::SendError;
+#[cfg(feature = "server")]
+use crate::disconnect::DisconnectKind;
+#[cfg(feature = "server")]
+use crate::request::UpgradeRequest;
+#[cfg(feature = "server")]
+use crate::hub::Hub;
+#[cfg(feature = "server")]
+use crate::presence::PresenceEvent;
+#[cfg(feature = "server")]
+use crate::queue::Outbox;
+#[cfg(feature = "server")]
+use crate::session::Session;
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+#[cfg(any(feature = "server", feature = "client", feature = "layers", feature = "interception"))]
 use std::pin::Pin;
-use std::str::FromStr;
 use std::sync::Arc;
+#[cfg(feature = "server")]
+use std::net::SocketAddr;
+#[cfg(feature = "server")]
+use std::time::{Duration, Instant};
+#[cfg(any(feature = "server", feature = "client"))]
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "server")]
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+#[cfg(feature = "server")]
+use tokio_tungstenite::tungstenite::Utf8Bytes;
 use uuid::Uuid;
 
 pub type State<S> = Arc<S>;
 
+pub use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+//the protocol limits and buffer sizes of the websockets, for Server::websocket_config() and Connector::websocket_config()
+pub use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+#[cfg(any(feature = "server", feature = "client", feature = "layers", feature = "interception"))]
+pub(crate) type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+#[cfg(any(feature = "server", feature = "client"))]
+pub(crate) type Callback<D, S> = Arc<dyn Fn(Params, D, State<S>) -> BoxFuture<()> + Send + Sync + 'static>;
+
+#[cfg(feature = "client")]
 pub struct Route<S>
 {
     pub(crate) name: String, //@NAME
-    pub(crate) callback: Arc<dyn Fn(Params, Dispatcher, State<S>) -> Pin<Box<dyn Future<Output=()> + Send>> + Send + Sync + 'static>
+    pub(crate) callback: Callback<Dispatcher, S>,
 }
 
+#[cfg(feature = "server")]
 pub struct ServerRoutes<S>
 {
     pub(crate) name: String, //@NAME
-    pub(crate) callback: Arc<dyn Fn(Params, ServerDispatcher, State<S>) -> Pin<Box<dyn Future<Output=()> + Send>> + Send + Sync + 'static>
+    pub(crate) callback: Callback<ServerDispatcher, S>,
 }
 
+//any stream a websocket can run on (tcp, tls, unix sockets, ...)
+#[cfg(any(feature = "server", feature = "client"))]
+pub(crate) trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+#[cfg(any(feature = "server", feature = "client"))]
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
 pub type Params = HashMap<String, String>;
 
 #[derive(Clone)]
@@ -27,8 +71,15 @@ pub struct Dispatcher {
 
 
 impl Dispatcher {
+    //the message is dropped if the connection is gone, see try_send()
     pub fn send(&self, msg: impl Into<String>) {
-        let _ = self.sender.send(msg.into()).unwrap();
+        let _ = self.sender.send(msg.into());
+    }
+
+    //same as send(), but tells if the message couldn't be queued (SendError::Closed)
+    #[cfg(feature = "client")]
+    pub fn try_send(&self, msg: impl Into<String>) -> Result<(), SendError> {
+        self.sender.send(msg.into()).map_err(|_| SendError::Closed)
     }
 
     pub async fn keep_alive(&self) {
@@ -37,31 +88,263 @@ impl Dispatcher {
 
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
-pub struct ConnectionId(pub Uuid);
+//the id of a connection, a random uuid by default
+//it can be anything unique (a ulid, a counter, the user's id), see Server::connection_id()
+#[derive(Clone, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(Arc<str>);
+
+impl ConnectionId {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into().into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for ConnectionId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<Uuid> for ConnectionId {
+    fn from(id: Uuid) -> Self {
+        Self::new(id.to_string())
+    }
+}
+
+impl From<String> for ConnectionId {
+    fn from(id: String) -> Self {
+        Self::new(id)
+    }
+}
+
+impl From<&str> for ConnectionId {
+    fn from(id: &str) -> Self {
+        Self::new(id)
+    }
+}
+
+impl From<&String> for ConnectionId {
+    fn from(id: &String) -> Self {
+        Self::new(id.as_str())
+    }
+}
 
+impl From<&ConnectionId> for ConnectionId {
+    fn from(id: &ConnectionId) -> Self {
+        id.clone()
+    }
+}
+
+//a snapshot of a connected client, see ServerDispatcher::connection_info()
+#[cfg(feature = "server")]
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub peer: SocketAddr,
+    //with session resumption, the first connect of the session
+    pub connected_at: Instant,
+    //when the client sent its last message
+    pub last_activity: Instant,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    //the round-trip time of the last ping (needs the heartbeat to be set on the server)
+    pub latency: Option<Duration>,
+    //the presence status ("online" by default)
+    pub status: String,
+}
+
+#[cfg(feature = "server")]
 #[derive(Clone)]
 pub struct ServerDispatcher {
-    pub(crate) sender: tokio::sync::mpsc::UnboundedSender<String>,
-    pub(crate) global_disp: tokio::sync::mpsc::UnboundedSender<GlobalDisp>,
+    pub(crate) id: ConnectionId,
+    pub(crate) outbox: Arc<Outbox>,
+    pub(crate) hub: Arc<Hub>,
+    pub(crate) session: Session,
+    pub(crate) request: Arc<UpgradeRequest>,
 }
 
+#[cfg(feature = "server")]
 impl ServerDispatcher {
+    //the id of the connection this dispatcher belongs to (same as the uuid parameter)
+    pub fn id(&self) -> &ConnectionId {
+        &self.id
+    }
+
+    //the per-connection session, shared by the layers and routes of this client
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    //the http upgrade request of the connection: path, query, headers, cookies and peer address
+    pub fn request(&self) -> &UpgradeRequest {
+        &self.request
+    }
+
+    //queues the message for this client, if the queue is full the server's QueuePolicy applies
+    //the message is dropped silently if it can't be queued, see try_send()
     pub fn send(&self, msg: impl Into<String>) {
-        let _ = self.sender.send(msg.into()).unwrap();
+        let _ = self.try_send(msg);
     }
 
-    pub fn send_to(&self, msg: impl Into<String>, uuid: impl Into<String>) {
-        let uuid = Uuid::from_str(&uuid.into()).unwrap();
-        let gd = GlobalDisp {
-            to: uuid,
-            msg: msg.into(),
+    //same as send(), but tells why the message couldn't be queued (Closed or QueueFull)
+    pub fn try_send(&self, msg: impl Into<String>) -> Result<(), SendError> {
+        self.outbox.push(msg.into().into())
+    }
+
+    //same as try_send(), but with QueuePolicy::Wait it waits until the queue has space (backpressure)
+    pub async fn send_async(&self, msg: impl Into<String>) -> Result<(), SendError> {
+        self.outbox.push_wait(msg.into().into()).await
+    }
+
+    //sends the message to the connection with the id (a ConnectionId or the uuid parameter)
+    pub fn send_to(&self, msg: impl Into<String>, id: impl Into<ConnectionId>) {
+        let gd = GlobalDisp::To {
+            to: id.into(),
+            msg: msg.into().into(),
         };
-        self.global_disp.send(gd).unwrap();
+        self.hub.deliver(gd);
+    }
+
+    //same as send_to(), but tells why the message couldn't be queued (UnknownConnection, Closed or QueueFull)
+    pub fn try_send_to(&self, msg: impl Into<String>, id: impl Into<ConnectionId>) -> Result<(), SendError> {
+        self.hub.send_to(&id.into(), msg.into().into())
+    }
+
+    //closes this connection with CloseCode::Normal and the reason, after the messages queued before
+    //DISCONNECTED runs with the reason, the connection isn't kept for session resumption
+    pub fn close(&self, reason: impl Into<String>) {
+        let frame = CloseFrame {
+            code: CloseCode::Normal,
+            reason: reason.into().into(),
+        };
+        self.outbox.request_close(frame, DisconnectKind::Kicked);
+    }
+
+    //same as close(), but for any connected client and with the given close code (e.g. CloseCode::Policy)
+    pub fn close_client(
+        &self,
+        id: impl Into<ConnectionId>,
+        code: CloseCode,
+        reason: impl Into<String>,
+    ) -> Result<(), SendError> {
+        let frame = CloseFrame {
+            code,
+            reason: reason.into().into(),
+        };
+        self.hub.close(&id.into(), frame)
+    }
+
+    //adds this connection to the room, it's removed automatically when it disconnects
+    pub fn join(&self, room: impl Into<String>) {
+        let room = room.into();
+        if self.hub.rooms.lock().unwrap().join(room.clone(), self.id.clone()) {
+            self.hub.announce(&self.id, PresenceEvent::Joined(room), &[]);
+        }
+    }
+
+    pub fn leave(&self, room: impl Into<String>) {
+        let room = room.into();
+        if self.hub.rooms.lock().unwrap().leave(&room, &self.id) {
+            self.hub.announce(&self.id, PresenceEvent::Left(room), &[]);
+        }
+    }
+
+    //sends the message to everyone in the room (including this connection, if it joined)
+    pub fn broadcast_to_room(&self, room: impl Into<String>, msg: impl Into<String>) {
+        let gd = GlobalDisp::Room {
+            room: room.into(),
+            msg: msg.into().into(),
+        };
+        self.hub.deliver(gd);
+    }
+
+    //sends the message to every connected client (including this one)
+    pub fn broadcast(&self, msg: impl Into<String>) {
+        let gd = GlobalDisp::Broadcast {
+            msg: msg.into().into(),
+            except: None,
+        };
+        self.hub.deliver(gd);
+    }
+
+    //sends the message to every connected client, except this one
+    pub fn broadcast_except_self(&self, msg: impl Into<String>) {
+        let gd = GlobalDisp::Broadcast {
+            msg: msg.into().into(),
+            except: Some(self.id.clone()),
+        };
+        self.hub.deliver(gd);
+    }
+
+    //the rooms this connection is in
+    pub fn rooms(&self) -> Vec<String> {
+        self.hub.rooms.lock().unwrap().rooms_of(&self.id)
+    }
+
+    pub fn in_room(&self, room: impl Into<String>) -> bool {
+        self.hub.rooms.lock().unwrap().contains(&room.into(), &self.id)
+    }
+
+    //the round-trip time of the last ping to this client (needs the heartbeat to be set on the server)
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency_of(self.id.clone())
+    }
+
+    //the round-trip time of the last ping to any connected client
+    pub fn latency_of(&self, id: impl Into<ConnectionId>) -> Option<Duration> {
+        self.hub
+            .connections
+            .lock()
+            .unwrap()
+            .get(&id.into())
+            .and_then(|connection| connection.latency)
+    }
+
+    //the ids of every connected client (clients that are away during session resumption aren't listed)
+    pub fn connection_ids(&self) -> Vec<ConnectionId> {
+        self.hub.ids()
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.hub.count()
+    }
+
+    pub fn is_online(&self, id: impl Into<ConnectionId>) -> bool {
+        self.hub.is_online(&id.into())
+    }
+
+    //changes the presence status of this connection (e.g. "away"), the interested clients get a status event
+    pub fn set_status(&self, status: impl Into<String>) {
+        self.hub.set_status(&self.id, status.into());
+    }
+
+    //the presence status of a connected client, None if it isn't online
+    pub fn status_of(&self, id: impl Into<ConnectionId>) -> Option<String> {
+        self.hub.status(&id.into())
+    }
+
+    //peer address, connect time, message counters and last activity of a connected client
+    pub fn connection_info(&self, id: impl Into<ConnectionId>) -> Option<ConnectionInfo> {
+        self.hub.info(&id.into())
+    }
+
+    //every connection in the room
+    pub fn room_members(&self, room: impl Into<String>) -> Vec<ConnectionId> {
+        self.hub.rooms.lock().unwrap().members(&room.into())
     }
 }
 
-pub(crate) struct GlobalDisp {
-    pub(crate) msg: String,
-    pub(crate) to: Uuid,
-}
\ No newline at end of file
+//messages routed to the connections by the hub
+//the message is converted to Utf8Bytes once, every recipient gets a cheap clone of the same bytes
+#[cfg(feature = "server")]
+pub(crate) enum GlobalDisp {
+    To { to: ConnectionId, msg: Utf8Bytes },
+    Room { room: String, msg: Utf8Bytes },
+    //the members of several rooms, every member gets the message once
+    Rooms { rooms: Vec<String>, msg: Utf8Bytes },
+    Broadcast { msg: Utf8Bytes, except: Option<ConnectionId> },
+}
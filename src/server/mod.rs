@@ -1,19 +1,79 @@
+use crate::admission::{Admission, AdmissionHook, Counts, IdGenerator, Ids, Limits, Request, ReservedId, Slot, StatusCode};
+#[cfg(feature = "hyper")]
+use crate::embed::Upgrader;
+use crate::error::ServerError;
+use crate::handler::{into_callback, Handler};
 use crate::interceptor::{InterceptorResult, InterceptorType, ServerInterceptor};
 use crate::layer::LayerResult::{Cancel, Pass};
 use crate::layer::ServerLayer;
 use crate::parser::Parsed;
-use crate::routes::{ConnectionId, GlobalDisp, Params, ServerDispatcher, ServerRoutes, State};
-use futures_util::{SinkExt, StreamExt};
+use crate::request::UpgradeRequest;
+use crate::backplane::Backplane;
+use crate::disconnect::{Disconnect, DisconnectKind};
+use crate::handle::ServerHandle;
+use crate::presence::{Presence, PresenceEvent};
+use crate::hub::{Connection, Hub, Stats};
+use crate::queue::{Outbox, Outgoing, QueuePolicy};
+use crate::resume::{Resumed, Resumption, Suspended, RESUME_COMMAND, RESUME_HEADER};
+use crate::router::Router;
+use crate::ratelimit::{RateLimit, RateLimitPolicy, TokenBucket};
+use crate::session::Session;
+use crate::routes::{BoxFuture, Io, ConnectionId, ConnectionInfo, Params, ServerDispatcher, ServerRoutes, State};
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, Stream, StreamExt};
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::ops::Deref;
+#[cfg(unix)]
+use std::path::Path;
 
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use tokio::sync::Mutex;
-use tokio::sync::mpsc::UnboundedSender;
-use tokio_tungstenite::accept_async;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::{Mutex, oneshot, watch};
+use tokio::sync::mpsc::WeakSender;
+use tokio::time::{Instant, Interval};
+use tokio_tungstenite::{accept_hdr_async_with_config, WebSocketStream};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Response};
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
 use uuid::Uuid;
+#[cfg(feature = "deflate")]
+use crate::deflate::{self, Deflate};
+#[cfg(feature = "deflate")]
+use tokio_tungstenite::tungstenite::http::HeaderMap;
+#[cfg(feature = "deflate")]
+use tokio_tungstenite::tungstenite::protocol::Role;
+
+#[cfg(feature = "native-tls")]
+pub use native_tls::Identity;
+
+pub(crate) type ErrorHook<S> =
+    Arc<dyn Fn(ServerError, SocketAddr, State<S>) -> BoxFuture<()> + Send + Sync + 'static>;
+
+pub(crate) type WebSocket = WebSocketStream<Box<dyn Io>>;
+
+//ping settings of the server
+#[derive(Clone, Copy)]
+struct Heartbeat {
+    interval: Duration,
+    pong_timeout: Duration,
+}
+
+//the phases of a graceful shutdown, broadcast to every connection
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Shutdown {
+    Running,
+    //no new connections and no new incoming messages, goodbye is sent
+    Draining,
+    //the websockets get closed
+    Closing,
+}
 
 pub struct Server<S> {
     url: String,
@@ -22,7 +82,38 @@ pub struct Server<S> {
     layers: Vec<ServerLayer<S>>,
     incoming_ir: Arc<Option<ServerInterceptor<S>>>,
     outgoing_ir: Arc<Option<ServerInterceptor<S>>>,
-    connections: Arc<Mutex<HashMap<Uuid, UnboundedSender<String>>>>,
+    hub: Arc<Hub>,
+    //set by ServerHandle::shutdown()
+    stop: Arc<watch::Sender<bool>>,
+    mounts: HashMap<String, Arc<dyn Mount>>,
+    //true once a route was added directly to the server
+    has_routes: bool,
+    goodbye: Option<String>,
+    grace_period: Duration,
+    handshake_timeout: Duration,
+    heartbeat: Option<Heartbeat>,
+    idle_timeout: Option<Duration>,
+    resume_window: Option<Duration>,
+    resume_by_id: bool,
+    queue_capacity: Option<usize>,
+    queue_policy: QueuePolicy,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    //the connection ids and counts and the suspended connections, shared by every listener
+    //(serve(), serve_unix(), upgrader()...)
+    ids: Arc<Ids>,
+    counts: Arc<std::sync::Mutex<Counts>>,
+    resumption: Arc<Resumption>,
+    admission: Option<AdmissionHook<S>>,
+    id_generator: Option<IdGenerator>,
+    rate_limit: Option<RateLimit>,
+    route_rate_limits: HashMap<String, RateLimit>,
+    error_hook: Option<ErrorHook<S>>,
+    websocket_config: WebSocketConfig,
+    #[cfg(feature = "deflate")]
+    deflate: bool,
+    #[cfg(feature = "native-tls")]
+    tls: Option<Identity>,
 }
 
 impl<S: Send + Sync + 'static> Server<S> {
@@ -35,10 +126,59 @@ impl<S: Send + Sync + 'static> Server<S> {
             layers: Vec::new(),
             incoming_ir: Arc::new(None),
             outgoing_ir: Arc::new(None),
-            connections: Arc::new(Mutex::new(HashMap::new())),
+            hub: Arc::new(Hub::default()),
+            stop: Arc::new(watch::Sender::new(false)),
+            mounts: HashMap::new(),
+            has_routes: false,
+            goodbye: None,
+            grace_period: Duration::from_secs(5),
+            handshake_timeout: Duration::from_secs(10),
+            heartbeat: None,
+            idle_timeout: None,
+            resume_window: None,
+            resume_by_id: false,
+            queue_capacity: None,
+            queue_policy: QueuePolicy::default(),
+            max_connections: None,
+            max_connections_per_ip: None,
+            ids: Arc::new(Ids::default()),
+            counts: Arc::new(std::sync::Mutex::new(Counts::default())),
+            resumption: Arc::new(Resumption::default()),
+            admission: None,
+            id_generator: None,
+            rate_limit: None,
+            route_rate_limits: HashMap::new(),
+            error_hook: None,
+            websocket_config: WebSocketConfig::default(),
+            #[cfg(feature = "deflate")]
+            deflate: false,
+            #[cfg(feature = "native-tls")]
+            tls: None,
         }
     }
 
+    //serves wss:// with the given certificate and private key
+    #[cfg(feature = "native-tls")]
+    pub fn with_tls(&mut self, identity: Identity) {
+        self.tls = Some(identity);
+    }
+
+    //message sent to every client when the server starts shutting down
+    pub fn goodbye(&mut self, msg: impl Into<String>) {
+        self.goodbye = Some(msg.into());
+    }
+
+    //how long the shutdown waits for the running handlers before closing the connections (default: 5s)
+    pub fn grace_period(&mut self, grace_period: Duration) {
+        self.grace_period = grace_period;
+    }
+
+    //how long a client has for the tls and the websocket handshake (default: 10s)
+    //slower clients are dropped and reported to the error hook as ServerError::HandshakeTimeout
+    pub fn handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
     pub fn intercept(&mut self, interceptor: ServerInterceptor<S>) {
         if interceptor.r#type == InterceptorType::INCOMING {
             self.incoming_ir = Arc::new(Some(interceptor));
@@ -51,235 +191,1192 @@ impl<S: Send + Sync + 'static> Server<S> {
         self.layers.push(layer);
     }
 
+    //pings every client in the given interval. if the pong doesn't arrive within pong_timeout,
+    //the connection is considered dead, it gets closed and DISCONNECTED runs with #reason 'ping timeout'
+    pub fn heartbeat(&mut self, interval: Duration, pong_timeout: Duration) {
+        self.heartbeat = Some(Heartbeat {
+            interval,
+            pong_timeout,
+        });
+    }
+
+    //closes the connections that didn't send any message for the given time (#reason 'idle timeout')
+    pub fn idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = Some(timeout);
+    }
+
+    //keeps the connections that dropped (closed, error or ping timeout) resumable for the window:
+    //their id, session and rooms are kept, and the messages sent to them are queued
+    //a client that reconnects with the resume token gets them back, DISCONNECTED only runs when the window expires
+    pub fn session_resumption(&mut self, window: Duration) {
+        self.resume_window = Some(window);
+    }
+
+    //with session_resumption(), an upgrade that gets the id of a suspended connection from the admission hook
+    //(or the id generator) resumes it without the token, instead of being rejected with 409
+    //only for ids that prove who the client is, e.g. AcceptAs with the user id after checking the session cookie
+    pub fn resume_by_id(&mut self) {
+        self.resume_by_id = true;
+    }
+
+    //bounds the outgoing queue of every connection (default: unbounded)
+    //the policy decides what happens to the messages sent to a client that doesn't read fast enough
+    pub fn outgoing_queue(&mut self, capacity: usize, policy: QueuePolicy) {
+        self.queue_capacity = Some(capacity);
+        self.queue_policy = policy;
+    }
+
+    //the protocol limits and buffer sizes of the websockets (max message and frame size, write buffers)
+    //a client that goes over the limits is disconnected
+    pub fn websocket_config(&mut self, config: WebSocketConfig) {
+        self.websocket_config = config;
+    }
+
+    //accepts permessage-deflate when the client offers it, the messages are compressed on the wire
+    //small messages and control frames are sent uncompressed
+    #[cfg(feature = "deflate")]
+    pub fn permessage_deflate(&mut self) {
+        self.deflate = true;
+    }
+
+    //upgrades beyond this number of connections are rejected with 503
+    pub fn max_connections(&mut self, max: usize) {
+        self.max_connections = Some(max);
+    }
+
+    //upgrades beyond this number of connections from the same ip are rejected with 429
+    pub fn max_connections_per_ip(&mut self, max: usize) {
+        self.max_connections_per_ip = Some(max);
+    }
+
+    //runs during the websocket handshake, before the client gets an id and CONNECTED runs
+    //returning Admission::Reject(status) refuses the upgrade with that http status,
+    //Admission::AcceptAs(id) accepts it with the given connection id
+    //the hook is synchronous and runs inside the handshake on a runtime thread, so it must not block:
+    //no database or network calls, check a cache kept in the state instead (or do the slow checks in CONNECTED)
+    pub fn admission<F>(&mut self, callback: F)
+    where
+        F: Fn(&UpgradeRequest, &State<S>) -> Admission + Send + Sync + 'static,
+    {
+        self.admission = Some(Arc::new(callback));
+    }
+
+    //generates the ids of the connections (default: a random uuid)
+    //the ids must be unique, an upgrade that gets an id already in use is refused with 409
+    pub fn connection_id<F>(&mut self, generator: F)
+    where
+        F: Fn(&UpgradeRequest) -> ConnectionId + Send + Sync + 'static,
+    {
+        self.id_generator = Some(Arc::new(generator));
+    }
+
+    //limits the incoming messages of every connection
+    pub fn rate_limit(&mut self, limit: RateLimit) {
+        self.rate_limit = Some(limit);
+    }
+
+    //limits the calls of one route, separately for every connection
+    pub fn route_rate_limit(&mut self, route: impl Into<String>, limit: RateLimit) {
+        self.route_rate_limits.insert(route.into(), limit);
+    }
+
+    //called when a connection fails before it could be routed (e.g. the handshake failed)
+    pub fn on_error<F, Fut>(&mut self, callback: F)
+    where
+        F: Fn(ServerError, SocketAddr, State<S>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.error_hook = Some(Arc::new(move |error, peer, state| {
+            Box::pin(callback(error, peer, state))
+        }));
+    }
+
     pub async fn route<F, Fut>(&mut self, name: impl Into<String>, callback: F)
     where
         F: Fn(Params, ServerDispatcher, State<S>) -> Fut + Send + Sync + 'static,
         Fut: Future<Output = ()> + Send + 'static,
     {
+        self.route_handler(name, callback).await;
+    }
+
+    //same as route(), but takes anything that implements Handler (e.g. a struct with its own fields)
+    pub async fn route_handler(
+        &mut self,
+        name: impl Into<String>,
+        handler: impl Handler<S, ServerDispatcher>,
+    ) {
         let name = name.into();
         self.routes.lock().await.push(ServerRoutes {
             name,
-            callback: Arc::new(move |params, dispatcher, state| {
-                Box::pin(callback(params, dispatcher, state))
-            }),
+            callback: into_callback(handler),
         });
+        self.has_routes = true;
     }
-    pub async fn serve(&self) {
-        //clones connections from self
-        let connections: Arc<Mutex<HashMap<Uuid, UnboundedSender<String>>>> =
-            self.connections.clone();
 
-        //creates a global dispatcher - to send msg from one client to the other
-        let (global_tx, mut global_rx) = tokio::sync::mpsc::unbounded_channel::<GlobalDisp>();
+    //serves the routes, layers and state of the router on the given upgrade path (e.g. /chat)
+    //once a router is mounted, the routes added directly to the server are served on "/" (unless a router is
+    //mounted there), and upgrades to paths without a router are rejected with 404
+    pub fn mount<T: Send + Sync + 'static>(&mut self, path: impl Into<String>, router: Router<T>) {
+        self.mounts.insert(mount_path(&path.into()), Arc::new(Endpoint::from(router)));
+    }
+
+    //tracks the presence of the clients and sends @PRESENCE events to the interested ones (see Presence)
+    pub fn presence(&mut self, presence: Presence) {
+        *self.hub.presence.write().unwrap() = Some(presence);
+    }
 
-        let connections_clone = self.connections.clone();
+    //connects this server to the other instances (see Backplane), send_to() reaches the clients of every instance
+    //room sends and broadcasts go to every instance, the connection queries only see the local clients
+    pub fn backplane(&mut self, backplane: impl Backplane) {
+        *self.hub.backplane.write().unwrap() = Some(Arc::new(backplane));
+    }
+
+    //a cloneable handle to send messages, query the clients and shut the server down from any task
+    //it can be taken before serve() and used while the server runs
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle::new(self.hub.clone(), self.stop.clone())
+    }
+
+    //the ids of every connected client, same as ServerDispatcher::connection_ids()
+    pub fn connection_ids(&self) -> Vec<ConnectionId> {
+        self.hub.ids()
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.hub.count()
+    }
+
+    pub fn is_online(&self, id: impl Into<ConnectionId>) -> bool {
+        self.hub.is_online(&id.into())
+    }
+
+    pub fn connection_info(&self, id: impl Into<ConnectionId>) -> Option<ConnectionInfo> {
+        self.hub.info(&id.into())
+    }
+
+    //starts the server without a listener, the connections come from an existing hyper or axum server
+    //must be called inside the tokio runtime
+    #[cfg(feature = "hyper")]
+    pub fn upgrader(&self) -> Upgrader<S> {
+        let upgrader = Upgrader::new(self.start());
+        //ServerHandle::shutdown() stops the upgrader as well
+        let mut stop = self.stop.subscribe();
+        let stopping = upgrader.clone();
         tokio::spawn(async move {
-            while let Some(msg) = global_rx.recv().await {
-                let locked = connections_clone.lock().await;
-                if locked.contains_key(&msg.to) {
-                    match locked.get(&msg.to).unwrap().send(msg.msg) {
-                        Ok(_) => (),
-                        Err(_) => (),
-                    }
-                }
+            if stop.wait_for(|stop| *stop).await.is_ok() {
+                stopping.shutdown().await;
             }
         });
+        upgrader
+    }
 
-        //clone everything from the self, that can't be used (can't move)
+    pub async fn serve(&self) -> Result<(), ServerError> {
+        self.serve_with_shutdown(futures_util::future::pending()).await
+    }
+
+    //serves until the signal future completes, then shuts down gracefully:
+    //stops accepting, sends the goodbye message, waits for the running handlers
+    //(at most for the grace period) and closes every websocket with a close frame
+    pub async fn serve_with_shutdown(
+        &self,
+        signal: impl Future<Output = ()>,
+    ) -> Result<(), ServerError> {
         println!("SERVING");
-        let layers = &self.layers;
-        let listener = TcpListener::bind(&self.url).await.unwrap();
-        let routes = self.routes.clone();
-        let state = self.state.clone();
+        let listener = TcpListener::bind(&self.url)
+            .await
+            .map_err(|source| ServerError::Bind {
+                addr: self.url.clone(),
+                source,
+            })?;
+        self.serve_listener_with_shutdown(listener, signal).await
+    }
 
-        //in this while, there's all the client's connected
-        while let Ok((stream, _)) = listener.accept().await {
-            //clone stuff before moving into the tokio::spawn
-            let routes = Arc::clone(&routes);
-            let state = state.clone();
-            let layers = layers.clone();
-            let connections = Arc::clone(&connections);
-            let tx_copy = global_tx.clone();
-            let interceptor = self.incoming_ir.clone();
-            let outgoing_ir = self.outgoing_ir.clone();
+    //serves on a listener that is already bound instead of the address of the server
+    //(e.g. bound on port 0, with the port taken from its local_addr())
+    pub async fn serve_listener(&self, listener: TcpListener) -> Result<(), ServerError> {
+        self.serve_listener_with_shutdown(listener, futures_util::future::pending()).await
+    }
+
+    pub async fn serve_listener_with_shutdown(
+        &self,
+        listener: TcpListener,
+        signal: impl Future<Output = ()>,
+    ) -> Result<(), ServerError> {
+        let incoming = futures_util::stream::poll_fn(move |cx| listener.poll_accept(cx).map(Some));
+        self.accept(incoming, signal).await
+    }
+
+    //serves on a unix domain socket instead of the tcp address (the socket file must not exist yet)
+    #[cfg(unix)]
+    pub async fn serve_unix(&self, path: impl AsRef<Path>) -> Result<(), ServerError> {
+        self.serve_unix_with_shutdown(path, futures_util::future::pending()).await
+    }
+
+    #[cfg(unix)]
+    pub async fn serve_unix_with_shutdown(
+        &self,
+        path: impl AsRef<Path>,
+        signal: impl Future<Output = ()>,
+    ) -> Result<(), ServerError> {
+        let path = path.as_ref();
+        let listener = UnixListener::bind(path).map_err(|source| ServerError::Bind {
+            addr: path.display().to_string(),
+            source,
+        })?;
+        let incoming = futures_util::stream::poll_fn(move |cx| {
+            listener
+                .poll_accept(cx)
+                .map(|accepted| Some(accepted.map(|(stream, _)| (stream, unknown_peer()))))
+        });
+        self.accept(incoming, signal).await
+    }
+
+    //serves the connections of any stream (e.g. a custom listener or in-process duplex streams)
+    //the server stops when the stream ends; the peer address of these connections is 0.0.0.0:0
+    pub async fn serve_incoming<I, IO>(&self, incoming: I) -> Result<(), ServerError>
+    where
+        I: Stream<Item = std::io::Result<IO>>,
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.serve_incoming_with_shutdown(incoming, futures_util::future::pending()).await
+    }
+
+    pub async fn serve_incoming_with_shutdown<I, IO>(
+        &self,
+        incoming: I,
+        signal: impl Future<Output = ()>,
+    ) -> Result<(), ServerError>
+    where
+        I: Stream<Item = std::io::Result<IO>>,
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let incoming = incoming.map(|accepted| accepted.map(|stream| (stream, unknown_peer())));
+        self.accept(incoming, signal).await
+    }
+
+    //accepts the connections until the signal completes (or the stream ends), then shuts down gracefully
+    async fn accept<I, IO>(&self, incoming: I, signal: impl Future<Output = ()>) -> Result<(), ServerError>
+    where
+        I: Stream<Item = std::io::Result<(IO, SocketAddr)>>,
+        IO: Io + 'static,
+    {
+        #[cfg(feature = "native-tls")]
+        let tls = match &self.tls {
+            Some(identity) => Some(Arc::new(tokio_native_tls::TlsAcceptor::from(
+                native_tls::TlsAcceptor::new(identity.clone()).map_err(ServerError::Tls)?,
+            ))),
+            None => None,
+        };
+        let running = self.start();
+
+        let mut incoming = Box::pin(incoming);
+        tokio::pin!(signal);
+        let mut stop = self.stop.subscribe();
+        let mut result = Ok(());
+
+        //in this loop, there's all the client's connected
+        loop {
+            let accepted = tokio::select! {
+                accepted = incoming.next() => accepted,
+                _ = &mut signal => break,
+                _ = stop.wait_for(|stop| *stop) => break,
+            };
+            let (stream, peer) = match accepted {
+                Some(Ok(accepted)) => accepted,
+                //a failed accept only ends the server if the listener itself is broken
+                Some(Err(e)) => match accept_backoff(&e) {
+                    Some(backoff) => {
+                        running.handshake.report(ServerError::Io(e), unknown_peer()).await;
+                        tokio::time::sleep(backoff).await;
+                        continue;
+                    }
+                    None => {
+                        result = Err(ServerError::Io(e));
+                        break;
+                    }
+                },
+                None => break,
+            };
+            let handshake = running.handshake.clone();
+            let conn_guard = running.conns_tx.clone();
+            #[cfg(feature = "native-tls")]
+            let tls = tls.clone();
             //spawns a new task for every client
             tokio::spawn(async move {
-                //tries to connect
-                let ws = match accept_async(stream).await {
-                    Ok(ws) => ws,
-                    Err(_) => return,
-                };
-                //split the stream
-                let (mut write, mut read) = ws.split();
-
-                //create an internal channel for communication between the crate and the user
-                let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<String>();
-
-                //create copy of the layers
-                let layers_copy = layers.clone();
-                let incoming_ir_copy = interceptor.clone();
-                let outgoing_ir_copy = outgoing_ir.clone();
-                //create uuid
-                let conn_id = ConnectionId(Uuid::new_v4());
-
-                //saves the connection to be able to call it
-                connections.lock().await.insert(conn_id.0, sender.clone());
-
-                //tries to find the CONNECTED route to send the msg
-                if let Some(route) = routes.lock().await.iter().find(|r| r.name == "CONNECTED") {
-                    let params: Params =
-                        Params::from([("uuid".to_string(), conn_id.0.to_string())]);
-                    let callback = route.callback.clone();
-                    let dispatcher = ServerDispatcher {
-                        sender: sender.clone(),
-                        global_disp: tx_copy.clone(),
-                    };
-                    let state = state.clone();
-
-                    //awaits to all the layers to pass. if they fail, then the route stops executing
-                    tokio::spawn(async move {
-                        if run_layer(
-                            "CONNECTED".to_string(),
-                            layers.as_ref(),
-                            dispatcher.clone(),
-                            state.clone(),
-                            params.clone(),
-                        )
-                        .await
-                        {
-                            println!("CONNECTED ALERT CALLED - RUN_LAYER PASSED");
-                            callback(params, dispatcher, state).await;
+                let _conn_guard = conn_guard;
+                #[cfg(feature = "native-tls")]
+                if let Some(tls) = tls {
+                    //gives up if the server shuts down or the client is too slow
+                    let accepted = tokio::select! {
+                        accepted = tls.accept(stream) => accepted,
+                        _ = tokio::time::sleep(handshake.handshake_timeout) => {
+                            handshake.report(ServerError::HandshakeTimeout, peer).await;
+                            return;
                         }
-                    });
+                        _ = handshake.shared.stopping() => return,
+                    };
+                    match accepted {
+                        Ok(stream) => handle_connection(handshake, stream, peer).await,
+                        Err(e) => handshake.report(ServerError::Tls(e), peer).await,
+                    }
+                    return;
                 }
+                handle_connection(handshake, stream, peer).await;
+            });
+        }
 
-                //creates listeners for the internal channel and for the ws
-                loop {
-                    tokio::select! {
+        //stops accepting (the listener is dropped) and lets the connections drain
+        drop(incoming);
+        running.shutdown().await;
+        result
+    }
 
+    //creates everything the connections need, except the listener
+    pub(crate) fn start(&self) -> Running<S> {
+        //shutdown phase for the connections
+        let (shutdown_tx, shutdown_rx) = watch::channel(Shutdown::Running);
+        //every running handler holds a sender, recv() returns None when all of them finished
+        let (handlers_tx, handlers_rx) = tokio::sync::mpsc::channel::<()>(1);
+        //same for the connection tasks
+        let (conns_tx, conns_rx) = tokio::sync::mpsc::channel::<()>(1);
 
-                        // outgoing
-                        Some(msg) = receiver.recv() => {
-                        let guard = outgoing_ir.clone();
-                            let msg:String = match guard.deref() {
-                            Some(interceptor) => {
-                                if let InterceptorResult::Pass(string) = (interceptor.callback)(msg.to_string(), conn_id.0.clone(), state.clone()).await {
-                                    string
-                                }
-                                    else {
-                                        #[cfg(feature = "debug")]
-                                        println!("INTERCEPTOR BLOCKED OUTGOING MESSAGE");
-                                        continue;
-                                }
-                            }
-                            None => {msg.to_string()}
-                        };
-                            let _ = write.send(Message::text(msg)).await;
+        //everything the connection tasks need, cloned from self once
+        let shared = Arc::new(Shared {
+            hub: self.hub.clone(),
+            goodbye: self.goodbye.clone(),
+            heartbeat: self.heartbeat,
+            idle_timeout: self.idle_timeout,
+            resume_window: self.resume_window,
+            queue_capacity: self.queue_capacity,
+            queue_policy: self.queue_policy,
+            resumption: self.resumption.clone(),
+            rate_limit: self.rate_limit,
+            route_rate_limits: self.route_rate_limits.clone(),
+            shutdown: shutdown_rx,
+            handlers: handlers_tx.downgrade(),
+        });
+        let root: Arc<dyn Mount> = Arc::new(Endpoint {
+            routes: self.routes.clone(),
+            state: self.state.clone(),
+            layers: self.layers.clone(),
+            incoming_ir: self.incoming_ir.clone(),
+            outgoing_ir: self.outgoing_ir.clone(),
+        });
+        //next to the mounted routers, the routes of the server are served on "/" (unless a router is mounted there)
+        let mut mounts = self.mounts.clone();
+        if !mounts.is_empty() && self.has_routes {
+            mounts.entry(mount_path("/")).or_insert_with(|| root.clone());
+        }
+        let handshake = Arc::new(Handshake {
+            shared,
+            state: self.state.clone(),
+            root,
+            mounts,
+            limits: Arc::new(Limits::new(
+                self.max_connections,
+                self.max_connections_per_ip,
+                self.counts.clone(),
+            )),
+            admission: self.admission.clone(),
+            id_generator: self.id_generator.clone(),
+            ids: self.ids.clone(),
+            resume_by_id: self.resume_by_id,
+            error_hook: self.error_hook.clone(),
+            handshake_timeout: self.handshake_timeout,
+            websocket_config: self.websocket_config,
+            #[cfg(feature = "deflate")]
+            deflate: self.deflate,
+        });
+        //delivers the messages of the other instances to the clients of this one
+        let subscriber = handshake.clone();
+        tokio::spawn(async move {
+            let report = |e| subscriber.report(ServerError::Backplane(e), unknown_peer());
+            subscriber.shared.hub.subscribe(subscriber.shared.stopping(), report).await
+        });
+
+        Running {
+            handshake,
+            shutdown_tx,
+            handlers_tx,
+            handlers_rx,
+            conns_tx,
+            conns_rx,
+            grace_period: self.grace_period,
+        }
+    }
+}
+
+//a started server: the connections can be handed over to the handshake,
+//the senders keep the shutdown waiting for the handlers and connection tasks
+pub(crate) struct Running<S> {
+    pub(crate) handshake: Arc<Handshake<S>>,
+    shutdown_tx: watch::Sender<Shutdown>,
+    handlers_tx: tokio::sync::mpsc::Sender<()>,
+    handlers_rx: tokio::sync::mpsc::Receiver<()>,
+    //every connection task holds a clone
+    pub(crate) conns_tx: tokio::sync::mpsc::Sender<()>,
+    conns_rx: tokio::sync::mpsc::Receiver<()>,
+    grace_period: Duration,
+}
+
+impl<S> Running<S> {
+    //sends the goodbye message, waits for the running handlers (at most for the grace period),
+    //then closes every websocket and waits for the connection tasks to finish
+    pub(crate) async fn shutdown(mut self) {
+        let _ = self.shutdown_tx.send(Shutdown::Draining);
+        drop(self.handlers_tx);
+        let _ = tokio::time::timeout(self.grace_period, self.handlers_rx.recv()).await;
+
+        let _ = self.shutdown_tx.send(Shutdown::Closing);
+        drop(self.conns_tx);
+        let _ = self.conns_rx.recv().await;
+    }
+}
+
+//the parts of the server every connection needs, whatever endpoint it's on
+pub(crate) struct Shared {
+    hub: Arc<Hub>,
+    goodbye: Option<String>,
+    heartbeat: Option<Heartbeat>,
+    idle_timeout: Option<Duration>,
+    resume_window: Option<Duration>,
+    queue_capacity: Option<usize>,
+    queue_policy: QueuePolicy,
+    resumption: Arc<Resumption>,
+    rate_limit: Option<RateLimit>,
+    route_rate_limits: HashMap<String, RateLimit>,
+    shutdown: watch::Receiver<Shutdown>,
+    //weak, so the connections alone don't keep the shutdown waiting
+    handlers: WeakSender<()>,
+}
+
+impl Shared {
+    //completes when the server starts shutting down
+    pub(crate) async fn stopping(&self) {
+        let _ = self.shutdown.clone().wait_for(|phase| *phase != Shutdown::Running).await;
+    }
+}
+
+//the parts of the server needed before a connection is upgraded
+pub(crate) struct Handshake<S> {
+    pub(crate) shared: Arc<Shared>,
+    state: State<S>,
+    //the routes added directly to the server
+    root: Arc<dyn Mount>,
+    mounts: HashMap<String, Arc<dyn Mount>>,
+    limits: Arc<Limits>,
+    admission: Option<AdmissionHook<S>>,
+    id_generator: Option<IdGenerator>,
+    ids: Arc<Ids>,
+    resume_by_id: bool,
+    error_hook: Option<ErrorHook<S>>,
+    handshake_timeout: Duration,
+    pub(crate) websocket_config: WebSocketConfig,
+    #[cfg(feature = "deflate")]
+    deflate: bool,
+}
+
+impl<S: Send + Sync + 'static> Handshake<S> {
+    //passes a connection error to the error hook (if there's one)
+    pub(crate) async fn report(&self, error: ServerError, peer: SocketAddr) {
+        if let Some(hook) = &self.error_hook {
+            hook(error, peer, self.state.clone()).await;
+        }
+    }
+
+    //true if the server uses permessage-deflate and the client offered it
+    #[cfg(feature = "deflate")]
+    pub(crate) fn deflate(&self, headers: &HeaderMap) -> bool {
+        self.deflate
+            && deflate::accepts(
+                headers
+                    .get_all("sec-websocket-extensions")
+                    .iter()
+                    .filter_map(|value| value.to_str().ok()),
+            )
+    }
+
+    //true if the upgrade paths go to the same endpoint
+    fn same_endpoint(&self, a: &str, b: &str) -> bool {
+        self.mounts.is_empty() || mount_path(a) == mount_path(b)
+    }
+
+    //the endpoint of the upgrade path, every path goes to the server's routes if nothing is mounted
+    //otherwise they are mounted on "/" in start()
+    fn endpoint(&self, path: &str) -> Option<Arc<dyn Mount>> {
+        if self.mounts.is_empty() {
+            return Some(self.root.clone());
+        }
+        self.mounts.get(&mount_path(path)).cloned()
+    }
+
+    //checks the path, the admission hook and the connection limits, and gives the connection an id
+    //returns the status code to reject the upgrade with
+    pub(crate) fn admit(&self, request: &UpgradeRequest) -> Result<Admitted, StatusCode> {
+        let endpoint = self.endpoint(&request.path).ok_or(StatusCode::NOT_FOUND)?;
+        let admission = match &self.admission {
+            Some(hook) => hook(request, &self.state),
+            None => Admission::Accept,
+        };
+        let id = match admission {
+            Admission::Accept => None,
+            Admission::AcceptAs(id) => Some(id),
+            Admission::Reject(status) => return Err(status),
+        };
+
+        //a client with a valid resume token gets back its id, its session and the messages it missed
+        //it takes over the slot of the suspended connection, so it doesn't count twice against the limits
+        //the token only resumes the connection on the endpoint it was suspended on
+        let same_endpoint = |suspended: &Suspended| self.same_endpoint(&suspended.path, &request.path);
+        let resumed = match (self.shared.resume_window, request.header(RESUME_HEADER)) {
+            (Some(_), Some(token)) => self.shared.resumption.take_if(token, same_endpoint),
+            _ => None,
+        };
+        if let Some(suspended) = resumed {
+            return Ok(Admitted::resumed(endpoint, suspended));
+        }
+
+        let id = id.unwrap_or_else(|| match &self.id_generator {
+            Some(generator) => generator(request),
+            None => ConnectionId::from(Uuid::new_v4()),
+        });
+        //with resume_by_id(), the id of a suspended connection (e.g. AcceptAs without the token) resumes it too
+        if self.resume_by_id
+            && let Some(suspended) = self.shared.resumption.take_id(&id, same_endpoint)
+        {
+            return Ok(Admitted::resumed(endpoint, suspended));
+        }
+        let slot = self.limits.acquire(request.peer.ip())?;
+        let id = self.ids.reserve(id).ok_or(StatusCode::CONFLICT)?;
+        Ok(Admitted {
+            endpoint,
+            id,
+            slot,
+            resumed: None,
+            took_over: None,
+        })
+    }
+}
+
+//an accepted upgrade, the id and the slot are held until the connection ends
+pub(crate) struct Admitted {
+    endpoint: Arc<dyn Mount>,
+    id: ReservedId,
+    slot: Slot,
+    resumed: Option<Resumed>,
+    //tells the task of the suspended connection that this one took over
+    //dropped without sending if the upgrade fails, then the suspended connection is cleaned up
+    took_over: Option<oneshot::Sender<()>>,
+}
+
+impl Admitted {
+    fn resumed(endpoint: Arc<dyn Mount>, suspended: Suspended) -> Self {
+        Self {
+            endpoint,
+            id: suspended.id,
+            slot: suspended.slot,
+            resumed: Some(suspended.resumed),
+            took_over: Some(suspended.took_over),
+        }
+    }
+
+    //runs the connection on the endpoint until it disconnects
+    pub(crate) async fn run(self, shared: Arc<Shared>, ws: WebSocket, request: UpgradeRequest) {
+        let Admitted {
+            endpoint,
+            id,
+            slot,
+            resumed,
+            took_over,
+        } = self;
+        if let Some(took_over) = took_over {
+            let _ = took_over.send(());
+        }
+        endpoint.run(shared, ws, request, id, slot, resumed).await;
+    }
+}
+
+//the routes, layers, interceptors and state of one upgrade path
+struct Endpoint<S> {
+    routes: Arc<Mutex<Vec<ServerRoutes<S>>>>,
+    state: State<S>,
+    layers: Vec<ServerLayer<S>>,
+    incoming_ir: Arc<Option<ServerInterceptor<S>>>,
+    outgoing_ir: Arc<Option<ServerInterceptor<S>>>,
+}
+
+impl<S> From<Router<S>> for Endpoint<S> {
+    fn from(router: Router<S>) -> Self {
+        Self {
+            routes: Arc::new(Mutex::new(router.routes)),
+            state: router.state,
+            layers: router.layers,
+            incoming_ir: router.incoming_ir,
+            outgoing_ir: router.outgoing_ir,
+        }
+    }
+}
+
+impl<S: Send + Sync + 'static> Endpoint<S> {
+    //finds the route by name and runs it with the layers in a new task
+    async fn spawn_route(&self, shared: &Shared, name: &str, params: Params, dispatcher: ServerDispatcher) {
+        let Some(route) = self.routes.lock().await.iter().find(|r| r.name == name).map(|r| r.callback.clone()) else {
+            return;
+        };
+        let name = name.to_string();
+        let layers = self.layers.clone();
+        let state = self.state.clone();
+        let handler_guard = shared.handlers.upgrade();
+
+        //awaits to all the layers to pass. if they fail, then the route stops executing
+        tokio::spawn(async move {
+            let _handler_guard = handler_guard;
+            if run_layer(name, layers.as_ref(), dispatcher.clone(), state.clone(), params.clone()).await {
+                route(params, dispatcher, state).await;
+            }
+        });
+    }
+}
+
+//an endpoint with its state type erased, so endpoints with different states fit in one map
+pub(crate) trait Mount: Send + Sync {
+    fn run(
+        self: Arc<Self>,
+        shared: Arc<Shared>,
+        ws: WebSocket,
+        request: UpgradeRequest,
+        id: ReservedId,
+        slot: Slot,
+        resumed: Option<Resumed>,
+    ) -> BoxFuture<()>;
+}
+
+impl<S: Send + Sync + 'static> Mount for Endpoint<S> {
+    fn run(
+        self: Arc<Self>,
+        shared: Arc<Shared>,
+        ws: WebSocket,
+        request: UpgradeRequest,
+        id: ReservedId,
+        slot: Slot,
+        resumed: Option<Resumed>,
+    ) -> BoxFuture<()> {
+        Box::pin(run_connection(shared, self, ws, request, id, slot, resumed))
+    }
+}
+
+//drives a single client from the websocket handshake until it disconnects
+//(the handshake callback has to return tungstenite's large ErrorResponse)
+#[allow(clippy::result_large_err)]
+async fn handle_connection<S, IO>(handshake: Arc<Handshake<S>>, stream: IO, peer: SocketAddr)
+where
+    S: Send + Sync + 'static,
+    IO: Io + 'static,
+{
+    //with permessage-deflate, the stream reads the extension from the response and compresses the frames
+    #[cfg(feature = "deflate")]
+    let stream: Box<dyn Io> = match handshake.deflate {
+        true => Box::new(Deflate::handshake(stream, Role::Server, &handshake.websocket_config)),
+        false => Box::new(stream),
+    };
+    #[cfg(not(feature = "deflate"))]
+    let stream: Box<dyn Io> = Box::new(stream);
+
+    //true if the path, the limits or the admission hook refused the upgrade
+    let mut rejected = false;
+    //the upgrade request and the accepted upgrade, held until the client disconnects
+    let mut upgrade = None;
+
+    //tries to connect, gives up if the server shuts down meanwhile
+    let ws = tokio::select! {
+        ws = accept_hdr_async_with_config(stream, |request: &Request, response: Response| {
+            #[cfg(feature = "deflate")]
+            let deflate = handshake.deflate(request.headers());
+            let request = UpgradeRequest::new(request.uri(), request.headers(), peer);
+            match handshake.admit(&request) {
+                Ok(admitted) => {
+                    upgrade = Some((request, admitted));
+                    #[cfg(feature = "deflate")]
+                    let response = accept_deflate(response, deflate);
+                    Ok(response)
+                }
+                Err(status) => {
+                    rejected = true;
+                    Err(reject(status))
+                }
+            }
+        }, Some(handshake.websocket_config)) => ws,
+        _ = tokio::time::sleep(handshake.handshake_timeout) => {
+            handshake.report(ServerError::HandshakeTimeout, peer).await;
+            return;
+        }
+        _ = handshake.shared.stopping() => return,
+    };
+    let ws = match ws {
+        Ok(ws) => ws,
+        Err(_) if rejected => return,
+        Err(e) => {
+            handshake.report(ServerError::Handshake(e), peer).await;
+            return;
+        }
+    };
+    let Some((request, admitted)) = upgrade else {
+        return;
+    };
+    admitted.run(handshake.shared.clone(), ws, request).await;
+}
+
+//runs the connection loop of an upgraded client on its endpoint
+async fn run_connection<S: Send + Sync + 'static>(
+    shared: Arc<Shared>,
+    endpoint: Arc<Endpoint<S>>,
+    ws: WebSocket,
+    request: UpgradeRequest,
+    id: ReservedId,
+    slot: Slot,
+    resumed: Option<Resumed>,
+) {
+    let mut shutdown = shared.shutdown.clone();
+    let state = endpoint.state.clone();
+    let conn_id = id.id.clone();
+    //split the stream
+    let (mut write, mut read) = ws.split();
+
+    //create the outgoing queue, for communication between the crate and the user
+    //a resumed connection continues with the queue of the old one, the missed messages are waiting in it
+    let is_resumed = resumed.is_some();
+    let Resumed {
+        outbox,
+        session,
+        connected_at,
+    } = resumed.unwrap_or_else(|| Resumed {
+        outbox: Arc::new(Outbox::new(shared.queue_capacity, shared.queue_policy)),
+        session: Session::default(),
+        connected_at: Instant::now(),
+    });
+
+    //saves the connection to be able to call it
+    let stats = Arc::new(Stats::new());
+    shared.hub.connections.lock().unwrap().insert(
+        conn_id.clone(),
+        Connection {
+            outbox: outbox.clone(),
+            latency: None,
+            peer: request.peer,
+            connected_at,
+            status: "online".to_string(),
+            stats: stats.clone(),
+        },
+    );
+    let dispatcher = ServerDispatcher {
+        id: conn_id.clone(),
+        outbox: outbox.clone(),
+        hub: shared.hub.clone(),
+        session,
+        request: Arc::new(request),
+    };
+
+    //the token to resume this connection with, a new one for every connection
+    let token = shared.resume_window.map(|_| {
+        let token = Resumption::token();
+        let _ = outbox.push(format!("{} #token {}", RESUME_COMMAND, token).into());
+        token
+    });
+
+    //a resumed client never went offline for the others
+    if !is_resumed {
+        shared.hub.announce(&conn_id, PresenceEvent::Connected, &[]);
+    }
+
+    //tries to find the CONNECTED route to send the msg
+    let mut params: Params = Params::from([("uuid".to_string(), conn_id.to_string())]);
+    if is_resumed {
+        params.insert("resumed".to_string(), "true".to_string());
+    }
+    endpoint.spawn_route(&shared, "CONNECTED", params, dispatcher.clone()).await;
+
+    //while draining, incoming messages are not read anymore
+    let mut draining = false;
+
+    //heartbeat: the first ping goes out after one interval
+    let mut ping_interval = shared.heartbeat.map(|heartbeat| {
+        tokio::time::interval_at(Instant::now() + heartbeat.interval, heartbeat.interval)
+    });
+    //payload and send time of the ping that wasn't answered yet
+    let mut pending_ping: Option<(u64, Instant)> = None;
+    let mut ping_count: u64 = 0;
+    let mut last_activity = Instant::now();
+
+    //rate limiting, the route buckets are created on the first call of the route
+    let mut bucket = shared.rate_limit.map(TokenBucket::new);
+    let mut route_buckets: HashMap<String, TokenBucket> = HashMap::new();
+
+    //creates listeners for the internal channel and for the ws
+    //the loop breaks with the reason of the disconnect
+    let mut disconnect = loop {
+        let pong_deadline = pending_ping
+            .zip(shared.heartbeat)
+            .map(|((_, sent), heartbeat)| sent + heartbeat.pong_timeout);
+        let idle_deadline = shared.idle_timeout.map(|timeout| last_activity + timeout);
+
+        tokio::select! {
+
+            // shutdown
+            Ok(()) = shutdown.changed() => {
+                let phase = *shutdown.borrow_and_update();
+                if !draining {
+                    draining = true;
+                    if let Some(goodbye) = &shared.goodbye {
+                        let _ = outbox.push(goodbye.as_str().into());
+                    }
+                }
+                if phase == Shutdown::Closing {
+                    //flush what the handlers sent before closing
+                    while let Some(msg) = outbox.try_recv() {
+                        if let Some(msg) = outgoing(endpoint.outgoing_ir.deref(), msg, conn_id.clone(), state.clone()).await {
+                            let _ = write.send(msg).await;
+                            stats.sent();
                         }
+                    }
+                    let frame = CloseFrame {
+                        code: CloseCode::Away,
+                        reason: Utf8Bytes::from_static("server shutting down"),
+                    };
+                    break close(&mut write, DisconnectKind::Shutdown, frame, connected_at).await;
+                }
+            }
 
+            // heartbeat
+            _ = tick(&mut ping_interval) => {
+                if pending_ping.is_none() {
+                    ping_count += 1;
+                    pending_ping = Some((ping_count, Instant::now()));
+                    let _ = write.send(Message::Ping(ping_count.to_be_bytes().to_vec().into())).await;
+                }
+            }
 
+            // no pong in time, the client is gone
+            _ = sleep_until(pong_deadline) => {
+                #[cfg(feature = "debug")]
+                println!("PING TIMEOUT");
+                let frame = CloseFrame {
+                    code: CloseCode::Away,
+                    reason: Utf8Bytes::from_static("ping timeout"),
+                };
+                break close(&mut write, DisconnectKind::PingTimeout, frame, connected_at).await;
+            }
 
-                         // incoming
-                        msg = read.next() =>{
-                            match msg {
-                                Some(Ok(Message::Close(_))) | None => {
-                                    #[cfg(feature = "debug")]
-                                    println!("MSG was close or none");
-                                    break;
-                                }
+            // the client didn't send anything for too long
+            _ = sleep_until(idle_deadline) => {
+                let frame = CloseFrame {
+                    code: CloseCode::Policy,
+                    reason: Utf8Bytes::from_static("idle timeout"),
+                };
+                break close(&mut write, DisconnectKind::IdleTimeout, frame, connected_at).await;
+            }
 
-                                Some(Ok(msg)) => {
-                                    #[cfg(feature = "debug")]
-                            println!("Received a message: {}", msg);
-                            let msg  = match msg {
-                                Message::Text(t) => t,
-                                Message::Close(_) => break,
-                                _ => Utf8Bytes::from(msg.to_string()),
-                            };
-
-                            let guard = incoming_ir_copy.clone();
-                            let msg:String = match guard.deref() {
-                            Some(interceptor) => {
-                                if let InterceptorResult::Pass(string) = (interceptor.callback)(msg.to_string(),conn_id.0.clone(), state.clone()).await {
-                                    string
-                                }
-                                else {
-                                    continue;
-                                }
+            // outgoing
+            msg = outbox.recv() => {
+                let msg = match msg {
+                    Outgoing::Message(msg) => msg,
+                    //close(), close_client() or a slow consumer (QueuePolicy::Disconnect)
+                    Outgoing::Close(frame, kind) => break close(&mut write, kind, frame, connected_at).await,
+                };
+                let Some(msg) = outgoing(endpoint.outgoing_ir.deref(), msg, conn_id.clone(), state.clone()).await else {
+                    #[cfg(feature = "debug")]
+                    println!("INTERCEPTOR BLOCKED OUTGOING MESSAGE");
+                    continue;
+                };
+                let _ = write.send(msg).await;
+                stats.sent();
+            }
+
+            // incoming
+            msg = read.next(), if !draining => {
+                match msg {
+                    Some(Ok(Message::Close(frame))) => {
+                        #[cfg(feature = "debug")]
+                        println!("MSG was close");
+                        break Disconnect::with_frame(DisconnectKind::Closed, frame.as_ref(), connected_at.elapsed());
+                    }
+
+                    None => {
+                        #[cfg(feature = "debug")]
+                        println!("MSG was none");
+                        break Disconnect::new(DisconnectKind::Closed, connected_at.elapsed());
+                    }
+
+                    Some(Ok(Message::Pong(payload))) => {
+                        if let Some((count, sent)) = pending_ping
+                            && payload.as_ref() == count.to_be_bytes()
+                        {
+                            pending_ping = None;
+                            if let Some(connection) = shared.hub.connections.lock().unwrap().get_mut(&conn_id) {
+                                connection.latency = Some(sent.elapsed());
                             }
-                            None => {msg.to_string()}
-                        };
+                        }
+                    }
 
-                            //tries to get the params and the command
-                            let mut parsed = Parsed::parse(msg.to_string());
-                            parsed.params.insert("uuid".to_string(), conn_id.0.to_string());
-                            if let Some(route) =
-                                routes.lock().await.iter().find(|r| r.name == parsed.command)
-                            {
-                                //runs the route
-                                let callback = route.callback.clone();
-                                let dispatcher = ServerDispatcher { sender: sender.clone(),
-                                global_disp: tx_copy.clone()};
-                                let state = state.clone();
-                                let name = parsed.command.clone();
-                                let layers = layers_copy.clone();
-                                tokio::spawn(async move {
-                                if run_layer(name, layers.clone().as_ref(), dispatcher.clone(), state.clone(), parsed.params.clone() ).await {
-                                    callback(parsed.params, dispatcher, state).await;
-                                }
-                                });
+                    Some(Ok(Message::Ping(_))) => {
+                        //tungstenite answers it, just make sure the pong goes out
+                        let _ = write.flush().await;
+                    }
+
+                    Some(Ok(msg)) => {
+                        #[cfg(feature = "debug")]
+                        println!("Received a message: {}", msg);
+                        last_activity = Instant::now();
+                        stats.received();
+                        if let Some(bucket) = &mut bucket
+                            && !bucket.try_take()
+                        {
+                            if rate_limited(bucket.policy(), &outbox) {
+                                break close(&mut write, DisconnectKind::RateLimited, rate_limit_close(), connected_at).await;
                             }
+                            continue;
+                        }
+                        let msg = match msg {
+                            Message::Text(t) => t,
+                            Message::Close(frame) => {
+                                break Disconnect::with_frame(DisconnectKind::Closed, frame.as_ref(), connected_at.elapsed());
+                            }
+                            _ => Utf8Bytes::from(msg.to_string()),
+                        };
 
-                                }
-                                Some(Err(e)) => {
-                                    #[cfg(feature = "debug")]
-                                    println!("MSG was Err:{}", e);
+                        let Some(msg) = intercept(endpoint.incoming_ir.deref(), msg.to_string(), conn_id.clone(), state.clone()).await else {
+                            continue;
+                        };
 
-                                    break;
+                        //tries to get the params and the command, then runs the route
+                        let mut parsed = Parsed::parse(msg);
+                        if let Some(limit) = shared.route_rate_limits.get(&parsed.command) {
+                            let bucket = route_buckets
+                                .entry(parsed.command.clone())
+                                .or_insert_with(|| TokenBucket::new(*limit));
+                            if !bucket.try_take() {
+                                if rate_limited(bucket.policy(), &outbox) {
+                                    break close(&mut write, DisconnectKind::RateLimited, rate_limit_close(), connected_at).await;
                                 }
+                                continue;
                             }
                         }
-                        else => {
-                            #[cfg(feature = "debug")]
-                            println!("CONNECTION CLOSED");
-                            break
-                        },
+                        parsed.params.insert("uuid".to_string(), conn_id.to_string());
+                        endpoint.spawn_route(&shared, &parsed.command, parsed.params, dispatcher.clone()).await;
+                    }
+                    Some(Err(e)) => {
+                        #[cfg(feature = "debug")]
+                        println!("MSG was Err:{}", e);
+
+                        break Disconnect::error(e, connected_at.elapsed());
                     }
                 }
+            }
+            else => {
+                #[cfg(feature = "debug")]
+                println!("CONNECTION CLOSED");
+                break Disconnect::new(DisconnectKind::Closed, connected_at.elapsed())
+            },
+        }
+    };
 
-                //when the code reaches here, the client disconnected...
-                connections.lock().await.remove(&conn_id.0);
-                if let Some(route) = routes
-                    .lock()
-                    .await
-                    .iter()
-                    .find(|r| r.name == "DISCONNECTED")
-                {
-                    let params: Params =
-                        Params::from([("uuid".to_string(), conn_id.0.to_string())]);
-                    let callback = route.callback.clone();
-                    let dispatcher = ServerDispatcher {
-                        sender: sender.clone(),
-                        global_disp: tx_copy.clone(),
-                    };
-                    let state = state.clone();
-                    let layers = layers_copy.clone();
-                    tokio::spawn(async move {
-                        if run_layer(
-                            "DISCONNECTED".to_string(),
-                            layers.as_ref(),
-                            dispatcher.clone(),
-                            state.clone(),
-                            params.clone(),
-                        )
-                        .await
-                        {
-                            callback(params, dispatcher, state).await;
-                        }
-                    });
+    //when the code reaches here, the client disconnected...
+    //if it can be resumed, the connection waits for the client to come back
+    //connections closed by the server (close(), close_client(), slow consumers) aren't resumable
+    let mut id = Some(id);
+    let mut slot = Some(slot);
+    if let (Some(window), Some(token)) = (shared.resume_window, token)
+        && matches!(
+            disconnect.kind,
+            DisconnectKind::Closed | DisconnectKind::Error | DisconnectKind::PingTimeout
+        )
+    {
+        let (took_over, mut resuming) = oneshot::channel();
+        let suspended = Suspended {
+            id: id.take().unwrap(),
+            slot: slot.take().unwrap(),
+            path: dispatcher.request.path.clone(),
+            resumed: Resumed {
+                outbox: outbox.clone(),
+                session: dispatcher.session.clone(),
+                connected_at,
+            },
+            took_over,
+        };
+        stats.suspended.store(true, Ordering::Relaxed);
+        shared.resumption.suspend(token.clone(), suspended);
+        //Some(true) if a new connection took over, Some(false) if its upgrade failed after taking the token
+        let woken = tokio::select! {
+            _ = tokio::time::sleep(window) => None,
+            took_over = &mut resuming => Some(took_over.is_ok()),
+            _ = shared.stopping() => None,
+            //closed by the server while the client was away
+            (frame, kind) = outbox.close_requested() => {
+                disconnect = Disconnect::with_frame(kind, Some(&frame), connected_at.elapsed());
+                None
+            }
+        };
+        let took_over = match woken {
+            Some(took_over) => took_over,
+            None => match shared.resumption.take(&token) {
+                //expired, the id and the slot are held until the connection is cleaned up
+                Some(expired) => {
+                    id = Some(expired.id);
+                    slot = Some(expired.slot);
+                    false
                 }
-            });
+                //a new connection is upgrading with the token right now, waits to see if it makes it
+                None => resuming.await.is_ok(),
+            },
+        };
+        if took_over {
+            return;
+        }
+    }
+
+    shared.hub.connections.lock().unwrap().remove(&conn_id);
+    let rooms = shared.hub.rooms.lock().unwrap().rooms_of(&conn_id);
+    shared.hub.rooms.lock().unwrap().leave_all(&conn_id);
+    outbox.close();
+    shared.hub.announce(&conn_id, PresenceEvent::Disconnected, &rooms);
+    let mut params = disconnect.params();
+    params.insert("uuid".to_string(), conn_id.to_string());
+    endpoint.spawn_route(&shared, "DISCONNECTED", params, dispatcher).await;
+    drop((id, slot));
+}
+
+//how long to wait after an accept error that doesn't break the listener, None if it does
+//errors of single connections are skipped, running out of file descriptors or memory waits a bit for them to free up
+fn accept_backoff(error: &std::io::Error) -> Option<Duration> {
+    use std::io::ErrorKind;
+
+    match error.kind() {
+        ErrorKind::ConnectionAborted
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionRefused
+        | ErrorKind::Interrupted
+        | ErrorKind::WouldBlock
+        | ErrorKind::TimedOut => Some(Duration::ZERO),
+        ErrorKind::OutOfMemory => Some(Duration::from_millis(100)),
+        _ if error.raw_os_error().is_some_and(|code| RESOURCE_ERRORS.contains(&code)) => Some(Duration::from_millis(100)),
+        _ => None,
+    }
+}
+
+//the os errors of accept() for running out of file descriptors or buffers, they differ between the platforms
+#[cfg(unix)]
+const RESOURCE_ERRORS: [i32; 3] = [libc::ENFILE, libc::EMFILE, libc::ENOBUFS];
+//WSAEMFILE and WSAENOBUFS
+#[cfg(windows)]
+const RESOURCE_ERRORS: [i32; 2] = [10024, 10055];
+#[cfg(not(any(unix, windows)))]
+const RESOURCE_ERRORS: [i32; 0] = [];
+
+//the peer address of connections that don't come from tcp
+fn unknown_peer() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 0))
+}
+
+//"chat", "/chat" and "/chat/" are the same path
+fn mount_path(path: &str) -> String {
+    format!("/{}", path.trim_matches('/'))
+}
+
+//runs the message through the interceptor, None means it was cancelled
+async fn intercept<S: Send + Sync + 'static>(
+    interceptor: &Option<ServerInterceptor<S>>,
+    msg: String,
+    id: ConnectionId,
+    state: State<S>,
+) -> Option<String> {
+    match interceptor {
+        Some(interceptor) => match (interceptor.callback)(msg, id, state).await {
+            InterceptorResult::Pass(string) => Some(string),
+            InterceptorResult::Cancel => None,
+        },
+        None => Some(msg),
+    }
+}
+
+//applies the policy of a rate limit, returns true if the connection has to be closed
+fn rate_limited(policy: RateLimitPolicy, outbox: &Outbox) -> bool {
+    match policy {
+        RateLimitPolicy::Drop => false,
+        RateLimitPolicy::Reply => {
+            let _ = outbox.push(Utf8Bytes::from_static("@RATE-LIMITED"));
+            false
         }
+        RateLimitPolicy::Disconnect => true,
+    }
+}
+
+fn rate_limit_close() -> CloseFrame {
+    CloseFrame {
+        code: CloseCode::Policy,
+        reason: Utf8Bytes::from_static("rate limited"),
+    }
+}
+
+//sends the close frame, the connection ends with the disconnect of the kind
+async fn close(
+    write: &mut SplitSink<WebSocket, Message>,
+    kind: DisconnectKind,
+    frame: CloseFrame,
+    connected_at: Instant,
+) -> Disconnect {
+    let disconnect = Disconnect::with_frame(kind, Some(&frame), connected_at.elapsed());
+    let _ = write.send(Message::Close(Some(frame))).await;
+    disconnect
+}
+
+//the http response of a refused upgrade
+fn reject(status: StatusCode) -> ErrorResponse {
+    let mut response = ErrorResponse::new(status.canonical_reason().map(str::to_string));
+    *response.status_mut() = status;
+    response
+}
+
+//adds the accepted permessage-deflate to the handshake response
+#[cfg(feature = "deflate")]
+fn accept_deflate(mut response: Response, deflate: bool) -> Response {
+    if deflate {
+        response
+            .headers_mut()
+            .insert("sec-websocket-extensions", deflate::RESPONSE.parse().unwrap());
+    }
+    response
+}
+
+//ticks the interval, or never completes if there's none
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => futures_util::future::pending().await,
+    }
+}
+
+//sleeps until the deadline, or never completes if there's none
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => futures_util::future::pending().await,
+    }
+}
+
+//turns a queued message into a websocket message
+//without an interceptor the (possibly shared) bytes are sent as they are
+async fn outgoing<S: Send + Sync + 'static>(
+    interceptor: &Option<ServerInterceptor<S>>,
+    msg: Utf8Bytes,
+    id: ConnectionId,
+    state: State<S>,
+) -> Option<Message> {
+    match interceptor {
+        Some(_) => intercept(interceptor, msg.to_string(), id, state)
+            .await
+            .map(Message::text),
+        None => Some(Message::Text(msg)),
     }
 }
 
@@ -296,7 +1393,7 @@ async fn run_layer<S: Send + Sync + 'static>(
         if layer.blocked.contains(&route) {
             return false;
         }
-        if !layer.allowed.contains(&route) && layer.allowed.len() > 0 {
+        if !layer.allowed.contains(&route) && !layer.allowed.is_empty() {
             return false;
         }
         let parsed = params.clone();